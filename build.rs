@@ -0,0 +1,29 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+// llvm-sys 38 links whichever LLVM its llvm-config reports. The code generator declares the C APIs
+// it lacks by hand and emits `syncscope` IR, both need LLVM 12 or later, the newest is
+// `LLVMGetTypeByName2`. Older versions are refused here rather than failing to link
+const MIN_LLVM_MAJOR: u32 = 12;
+
+// Looked up like llvm-sys does, on PATH first, then under LLVM_SYS_38_PREFIX
+fn llvm_version() -> Option<String> {
+    let mut configs = vec![PathBuf::from("llvm-config")];
+    if let Some(prefix) = env::var_os("LLVM_SYS_38_PREFIX") {
+        configs.push(PathBuf::from(prefix).join("bin").join("llvm-config"));
+    }
+    configs.iter().find_map(|config| Command::new(config).arg("--version").output().ok())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=LLVM_SYS_38_PREFIX");
+    // without llvm-config llvm-sys reports the missing LLVM itself
+    if let Some(version) = llvm_version() {
+        let major = version.split('.').next().and_then(|m| m.parse::<u32>().ok());
+        if major.map(|m| m < MIN_LLVM_MAJOR).unwrap_or(false) {
+            panic!("ruda needs LLVM {} or later, llvm-config reports {}", MIN_LLVM_MAJOR, version);
+        }
+    }
+}
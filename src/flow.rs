@@ -0,0 +1,86 @@
+use crate::parser::*;
+use crate::generics::type_name;
+
+/*
+Control flow of function bodies, checked before code generation :
+a body returns when one of its statements does, an `if` returns when the chain
+ends with an `else` and every one of its branches returns. Statements after one
+that returns can never run, they are reported and dropped so that nothing is
+generated after a terminator.
+*/
+
+// Statements carry no location of their own, the first call in them stands for it
+fn first_loc(expr: &BaseExpr) -> Option<SrcLoc> {
+    match expr {
+        BaseExpr::FuncCall(_, _, loc) | BaseExpr::Reduce { loc, .. } => Some(loc.clone()),
+        BaseExpr::LetDecl(_, _, value) | BaseExpr::Assign(_, value) | BaseExpr::Return(value) | BaseExpr::Member(value, _) => first_loc(&value.0),
+        BaseExpr::IfExpr(cond, body, _) => first_loc(&cond.0).or_else(|| body.iter().find_map(|e| first_loc(&e.0))),
+        _ => None
+    }
+}
+
+// Drops what follows a returning statement, and tells whether the body returns on every path
+fn prune(body: Vec<TypedExpr>, func: &str) -> (Vec<TypedExpr>, bool) {
+    let mut kept = vec![];
+    let mut iter = body.into_iter();
+    while let Some(expr) = iter.next() {
+        let (expr, returns) = prune_stmt(expr, func);
+        kept.push(expr);
+        if returns {
+            let rest: Vec<_> = iter.collect();
+            if !rest.is_empty() {
+                let loc = rest.iter().find_map(|e| first_loc(&e.0)).map(|l| format!("{}: ", l)).unwrap_or_default();
+                eprintln!("{}warning: unreachable code in `{}`, {} statement(s) after a return are ignored", loc, func, rest.len());
+            }
+            return (kept, true);
+        }
+    }
+    (kept, false)
+}
+
+fn prune_stmt(expr: TypedExpr, func: &str) -> (TypedExpr, bool) {
+    let (expr, ty) = expr;
+    match expr {
+        BaseExpr::Return(_) | BaseExpr::RetNull => ((expr, ty), true),
+        BaseExpr::IfExpr(cond, body, next) => {
+            let (body, returns) = prune(body, func);
+            let (next, next_returns) = prune_next(next, func);
+            ((BaseExpr::IfExpr(cond, body, next), ty), returns && next_returns)
+        }
+        BaseExpr::Else(Some(cond), body, next) => {
+            let (body, returns) = prune(body, func);
+            let (next, next_returns) = prune_next(next, func);
+            ((BaseExpr::Else(Some(cond), body, next), ty), returns && next_returns)
+        }
+        BaseExpr::Else(None, body, next) => {
+            let (body, returns) = prune(body, func);
+            ((BaseExpr::Else(None, body, next), ty), returns)
+        }
+        _ => ((expr, ty), false)
+    }
+}
+
+// A chain without a final `else` may skip every branch
+fn prune_next(next: Option<Box<TypedExpr>>, func: &str) -> (Option<Box<TypedExpr>>, bool) {
+    match next {
+        Some(next) => {
+            let (next, returns) = prune_stmt(*next, func);
+            (Some(Box::new(next)), returns)
+        }
+        None => (None, false)
+    }
+}
+
+// Unit functions may fall off their end, they get an implicit `return` during code generation
+pub fn check_flow(func: TypedExpr) -> Result<TypedExpr, String> {
+    match func.0 {
+        BaseExpr::FuncDecl { ident, para_in, ranges, is_par, launch, params, ret, body } => {
+            let (body, returns) = prune(body, &ident[..]);
+            if !returns && ret != TyName::Unit {
+                return Err(format!("Function `{}` returns {}, but not all paths return a value", ident, type_name(&ret)));
+            }
+            Ok((BaseExpr::FuncDecl { ident, para_in, ranges, is_par, launch, params, ret, body }, func.1))
+        }
+        _ => Ok(func)
+    }
+}
@@ -0,0 +1,429 @@
+use crate::parser::*;
+use crate::llvm_gen::*;
+use crate::intrinsics::{adopt_literals, check_intrinsic, check_reduce, split_options};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ptr::null_mut;
+use llvm::prelude::{LLVMContextRef, LLVMModuleRef, LLVMValueRef, LLVMBuilderRef};
+
+/*
+Generic functions are kept aside until they are used :
+    fun axpy<T: f32 | f64>(a: T, x: T, y: T) -> T { .. }
+    instance saxpy = axpy<f32>;
+The body is typechecked once, against every member of each bound, or against
+an opaque type when the parameter is unbounded. Calls infer the type arguments
+and instantiate a copy named `axpy_f32`, instances give that copy a name of its own.
+
+Interfaces name the functions a type has to provide, with `Self` standing for it :
+    interface Num { fun add(a: Self, b: Self) -> Self; .. }
+    impl Num for Complex { fun add(a: Complex, b: Complex) -> Complex { .. } .. }
+    impl Num for f32;
+Implementations are ordinary overloads, a bodyless `impl` only asserts they exist.
+An interface in a bound lets an opaque parameter use the functions it declares.
+*/
+pub(crate) struct Generics {
+    decls: HashMap<String, Vec<(Vec<TypeParam>, BaseExpr)>>,
+    instances: RefCell<HashMap<String, (LLVMValueRef, TyName)>>,
+    pending: RefCell<Vec<(TypedExpr, LLVMValueRef)>>,
+    interfaces: HashMap<String, Vec<(String, Vec<(String, TyName)>, TyName)>>,
+    impls: Vec<(String, TyName, Vec<String>)>,
+    assumed: RefCell<Vec<(String, TyName)>>,
+}
+
+pub(crate) fn substitute(ty: &TyName, bindings: &HashMap<String, TyName>) -> TyName {
+    match ty {
+        TyName::NameBind(name) => bindings.get(name).cloned().unwrap_or(ty.clone()),
+        TyName::Record(name, fields) => TyName::Record(name.clone(), fields.iter().map(|(n, t)| (n.clone(), substitute(t, bindings))).collect()),
+        TyName::MutBind(inner) => TyName::MutBind(Box::new(substitute(&**inner, bindings))),
+        TyName::Array(elem) => TyName::Array(Box::new(substitute(&**elem, bindings))),
+        TyName::Dense(elem, extents, layout) => TyName::Dense(Box::new(substitute(&**elem, bindings)), extents.clone(), *layout),
+        TyName::Arrow(params, ret) => TyName::Arrow(Box::new(substitute(&**params, bindings)), Box::new(substitute(&**ret, bindings))),
+        TyName::Tuple(items) => TyName::Tuple(items.iter().map(|t| substitute(t, bindings)).collect()),
+        _ => ty.clone()
+    }
+}
+
+// Substitutes every type written in a top level declaration
+pub(crate) fn substitute_decl(expr: TypedExpr, bindings: &HashMap<String, TyName>) -> TypedExpr {
+    let params = |params: Vec<(String, TyName)>| params.into_iter().map(|(n, ty)| (n, substitute(&ty, bindings))).collect::<Vec<_>>();
+    let decl = match expr.0 {
+        BaseExpr::FuncDecl { ident, para_in, ranges, is_par, launch, params: p, ret, body } =>
+            BaseExpr::FuncDecl { ident, para_in, ranges, is_par, launch, params: params(p), ret: substitute(&ret, bindings), body: substitute_body(body, bindings) },
+        BaseExpr::IntrinsicsFuncDecl(ident, p, ret, body) => BaseExpr::IntrinsicsFuncDecl(ident, params(p), substitute(&ret, bindings), body),
+        BaseExpr::FuncVirtualDecl(ident, p, ret) => BaseExpr::FuncVirtualDecl(ident, params(p), substitute(&ret, bindings)),
+        BaseExpr::GenericFuncDecl(type_params, func) => BaseExpr::GenericFuncDecl(type_params.into_iter()
+            .map(|(n, bound)| (n, bound.iter().map(|t| substitute(t, bindings)).collect())).collect(), Box::new(substitute_decl(*func, bindings))),
+        BaseExpr::Instance(name, generic, type_args) => BaseExpr::Instance(name, generic, type_args.iter().map(|t| substitute(t, bindings)).collect()),
+        BaseExpr::TypeDecl(name, ty) => BaseExpr::TypeDecl(name, substitute(&ty, bindings)),
+        BaseExpr::ConstDecl(name, ty) => BaseExpr::ConstDecl(name, substitute(&ty, bindings)),
+        BaseExpr::Interface(name, methods) => BaseExpr::Interface(name, methods.into_iter().map(|(n, p, ret)| (n, params(p), substitute(&ret, bindings))).collect()),
+        BaseExpr::Impl(name, ty, funcs) => BaseExpr::Impl(name, substitute(&ty, bindings), funcs.into_iter().map(|f| substitute_decl(f, bindings)).collect()),
+        other => other
+    };
+    (decl, substitute(&expr.1, bindings))
+}
+
+// Shared declarations are the only statements that name a type
+fn substitute_body(body: Vec<TypedExpr>, bindings: &HashMap<String, TyName>) -> Vec<TypedExpr> {
+    body.into_iter().map(|(expr, ty)| {
+        let expr = match expr {
+            BaseExpr::SharedDecl(name, shared) => BaseExpr::SharedDecl(name, substitute(&shared, bindings)),
+            BaseExpr::IfExpr(cond, body, next) => BaseExpr::IfExpr(cond, substitute_body(body, bindings), next.map(|n| Box::new(substitute_body(vec![*n], bindings).remove(0)))),
+            BaseExpr::Else(cond, body, next) => BaseExpr::Else(cond, substitute_body(body, bindings), next.map(|n| Box::new(substitute_body(vec![*n], bindings).remove(0)))),
+            other => other
+        };
+        (expr, ty)
+    }).collect()
+}
+
+pub(crate) fn type_name(ty: &TyName) -> String {
+    match ty {
+        TyName::NameBind(name) | TyName::Record(name, _) if !name.is_empty() => name.clone(),
+        TyName::MutBind(inner) => format!("mut {}", type_name(&**inner)),
+        TyName::Array(elem) => format!("[{}]", type_name(&**elem)),
+        TyName::Dense(elem, extents, _) => format!("[{}; {}]", type_name(&**elem), extents.join(", ")),
+        _ => format!("{:?}", ty)
+    }
+}
+
+fn mangle(ty: &TyName) -> String {
+    match ty {
+        TyName::NameBind(name) | TyName::Record(name, _) => name.clone(),
+        TyName::MutBind(inner) => mangle(&**inner),
+        TyName::Array(elem) | TyName::Dense(elem, _, _) => format!("arr_{}", mangle(&**elem)),
+        _ => String::from("unit")
+    }
+}
+
+fn strip_mut(ty: &TyName) -> &TyName {
+    if let TyName::MutBind(inner) = ty { &**inner } else { ty }
+}
+
+// Matches a parameter type against an argument type, a parameter bound twice takes the wider of both
+fn bind_type(param: &TyName, arg: &TyName, type_params: &Vec<TypeParam>, bindings: &mut HashMap<String, TyName>) -> Result<(), String> {
+    let arg = strip_mut(arg);
+    match param {
+        TyName::NameBind(name) if type_params.iter().any(|p| p.0.eq(name)) => {
+            let bound = match bindings.get(name) {
+                None => arg.clone(),
+                Some(prev) if subtype_check(arg, prev, &mut 0) => prev.clone(),
+                Some(prev) if subtype_check(prev, arg, &mut 0) => arg.clone(),
+                Some(prev) => return Err(format!("Type parameter `{}` is bound to both {:?} and {:?}", name, prev, arg))
+            };
+            bindings.insert(name.clone(), bound);
+            Ok(())
+        }
+        TyName::MutBind(inner) => bind_type(&**inner, arg, type_params, bindings),
+        TyName::Array(elem) | TyName::Dense(elem, _, _) => match arg {
+            TyName::Array(arg_elem) | TyName::Dense(arg_elem, _, _) => bind_type(&**elem, &**arg_elem, type_params, bindings),
+            _ => Err(format!("Expected an array for {:?}, {:?} given", param, arg))
+        },
+        _ => Ok(())
+    }
+}
+
+impl Generics {
+    pub fn new() -> Self {
+        Generics {
+            decls: HashMap::new(),
+            instances: RefCell::new(HashMap::new()),
+            pending: RefCell::new(vec![]),
+            interfaces: HashMap::new(),
+            impls: vec![],
+            assumed: RefCell::new(vec![]),
+        }
+    }
+
+    pub fn register_interface(&mut self, name: String, methods: Vec<(String, Vec<(String, TyName)>, TyName)>) {
+        if self.interfaces.insert(name.clone(), methods).is_some() {
+            panic!("Interface `{}` is declared twice", name);
+        }
+    }
+
+    pub fn register_impl(&mut self, interface: String, ty: TyName, funcs: Vec<String>) {
+        self.impls.push((interface, ty, funcs));
+    }
+
+    fn implements(&self, ty: &TyName, interface: &str) -> bool {
+        self.impls.iter().any(|i| i.0.eq(interface) && i.1 == *ty)
+            || self.assumed.borrow().iter().any(|i| i.0.eq(interface) && i.1 == *ty)
+    }
+
+    // Signatures an interface requires from `ty`
+    fn interface_methods(&self, interface: &str, ty: &TyName) -> Vec<(String, TyName)> {
+        let bindings: HashMap<_, _> = vec![(String::from("Self"), ty.clone())].into_iter().collect();
+        self.interfaces.get(interface).map(|methods| methods.iter().map(|(name, params, ret)| {
+            (name.clone(), TyName::Arrow(Box::new(TyName::Tuple(params.iter().map(|p| substitute(&p.1, &bindings)).collect())), Box::new(substitute(ret, &bindings))))
+        }).collect()).unwrap_or(vec![])
+    }
+
+    // Every implementation has to provide exactly the functions of its interface
+    pub fn check_impls(&self, module_decl: &HashMap<String, Vec<(LLVMValueRef, TyName)>>) {
+        for (interface, ty, funcs) in self.impls.iter() {
+            let methods = self.interfaces.get(interface).unwrap_or_else(|| panic!("Cannot implement unknown interface `{}` for `{}`", interface, type_name(ty)));
+            if let Some(extra) = funcs.iter().find(|f| !methods.iter().any(|m| m.0.eq(*f))) {
+                panic!("`{}` is not a member of interface `{}`", extra, interface);
+            }
+            for ((name, sig), (_, params, ret)) in self.interface_methods(interface, ty).into_iter().zip(methods.iter()) {
+                if !module_decl.get(&name).map(|decls| decls.iter().any(|d| d.1 == sig)).unwrap_or(false) {
+                    let params = params.iter().map(|p| format!("{}: {}", p.0, type_name(&p.1))).collect::<Vec<_>>().join(", ");
+                    panic!("`impl {} for {}` is missing `fun {}({}) -> {}` with Self = {}", interface, type_name(ty), name, params, type_name(ret), type_name(ty));
+                }
+            }
+        }
+    }
+
+    // Names the interface to implement when a call fails for want of one
+    fn missing_impl(&self, ident: &str, args: &Vec<TyName>) -> Option<String> {
+        let interface = self.interfaces.iter().find(|(_, methods)| methods.iter().any(|m| m.0.eq(ident)))?.0;
+        let ty = args.iter().find(|ty| !self.implements(strip_mut(ty), interface))?;
+        Some(format!("`{}` does not implement interface `{}`, which provides `{}`", type_name(strip_mut(ty)), interface, ident))
+    }
+
+    fn split_bound(&self, bound: &Vec<TyName>) -> (Vec<String>, Vec<TyName>) {
+        let (interfaces, members): (Vec<_>, Vec<_>) = bound.iter().cloned().partition(|ty| match ty {
+            TyName::NameBind(name) => self.interfaces.contains_key(name),
+            _ => false
+        });
+        (interfaces.into_iter().map(|i| type_name(&i)).collect(), members)
+    }
+
+    // Concrete members of a bound are alternatives, interfaces are all required.
+    // An argument outside of the members is promoted to the closest one it is a subtype of
+    fn satisfy_bound(&self, ty: &TyName, param: &TypeParam) -> Result<TyName, String> {
+        let (interfaces, members) = self.split_bound(&param.1);
+        let ty = if members.is_empty() || members.contains(ty) {
+            ty.clone()
+        } else {
+            members.iter().filter_map(|member| {
+                let mut steps = 0;
+                if subtype_check(ty, member, &mut steps) { Some((steps, member)) } else { None }
+            }).min_by_key(|v| v.0).map(|v| v.1.clone())
+                .ok_or_else(|| format!("{} does not satisfy the bound of type parameter `{}`", type_name(ty), param.0))?
+        };
+        match interfaces.iter().find(|i| !self.implements(&ty, i)) {
+            Some(interface) => Err(format!("{} does not implement interface `{}` required by type parameter `{}`", type_name(&ty), interface, param.0)),
+            None => Ok(ty)
+        }
+    }
+
+    // Parameters without concrete members in their bound are checked as an opaque type
+    fn bound_combinations(&self, type_params: &Vec<TypeParam>) -> Vec<Vec<TyName>> {
+        type_params.iter().fold(vec![vec![]], |combinations, param| {
+            let members = self.split_bound(&param.1).1;
+            let members = if members.is_empty() { vec![TyName::NameBind(param.0.clone())] } else { members };
+            combinations.iter().flat_map(|prefix| members.iter().map(move |member| {
+                let mut next = prefix.clone();
+                next.push(member.clone());
+                next
+            })).collect()
+        })
+    }
+
+    pub fn register(&mut self, type_params: Vec<TypeParam>, func: BaseExpr) {
+        if let BaseExpr::FuncDecl { ref ident, .. } = func {
+            self.decls.entry(ident.clone()).or_insert(vec![]).push((type_params, func));
+        }
+    }
+
+    pub fn pop_pending(&self) -> Option<(TypedExpr, LLVMValueRef)> {
+        self.pending.borrow_mut().pop()
+    }
+
+    // Type arguments, parameter types and return type of the generic `ident` called with `args`
+    pub fn infer(&self, ident: &str, args: &Vec<TyName>) -> Result<(Vec<TyName>, Vec<TyName>, TyName), String> {
+        let decls = self.decls.get(ident).ok_or_else(|| self.missing_impl(ident, args)
+            .unwrap_or_else(|| format!("No function `{}` accepts ({})", ident, args.iter().map(type_name).collect::<Vec<_>>().join(", "))))?;
+        let mut error = format!("No instance of `{}` accepts ({})", ident, args.iter().map(type_name).collect::<Vec<_>>().join(", "));
+        for (type_params, func) in decls.iter() {
+            if let BaseExpr::FuncDecl { params, ret, .. } = func {
+                if params.len() != args.len() {
+                    continue;
+                }
+                let mut bindings = HashMap::new();
+                let inferred = params.iter().zip(args.iter())
+                    .map(|(param, arg)| bind_type(&param.1, arg, type_params, &mut bindings))
+                    .collect::<Result<Vec<_>, _>>()
+                    .and_then(|_| type_params.iter().map(|param| {
+                        let ty = bindings.get(&param.0).ok_or_else(|| format!("Cannot infer type parameter `{}` of `{}`", param.0, ident))?;
+                        self.satisfy_bound(ty, param)
+                    }).collect::<Result<Vec<_>, _>>());
+                match inferred {
+                    Ok(type_args) => {
+                        let bindings: HashMap<_, _> = type_params.iter().map(|p| p.0.clone()).zip(type_args.iter().cloned()).collect();
+                        let param_types: Vec<_> = params.iter().map(|p| substitute(&p.1, &bindings)).collect();
+                        if param_types.iter().zip(args.iter()).all(|(param, arg)| subtype_check(arg, param, &mut 0)) {
+                            return Ok((type_args, param_types, substitute(ret, &bindings)));
+                        }
+                    }
+                    Err(e) => error = e
+                }
+            }
+        }
+        Err(error)
+    }
+
+    // Declares the instance of `ident` for `type_args` unless it exists already, its body is generated once pending instances are drained
+    pub fn instantiate(&self, ident: &str, type_args: &Vec<TyName>, name: Option<String>, context: LLVMContextRef, module: LLVMModuleRef, builder: LLVMBuilderRef) -> (LLVMValueRef, TyName) {
+        let mangled = format!("{}_{}", ident, type_args.iter().map(mangle).collect::<Vec<_>>().join("_"));
+        if let Some(instance) = self.instances.borrow().get(&mangled) {
+            return instance.clone();
+        }
+        let (type_params, func) = self.decls.get(ident).and_then(|decls| decls.iter().find(|d| d.0.len() == type_args.len()))
+            .unwrap_or_else(|| panic!("No generic `{}` takes {} type arguments", ident, type_args.len()));
+        for (param, arg) in type_params.iter().zip(type_args.iter()) {
+            match self.satisfy_bound(arg, param) {
+                Ok(ref ty) if ty == arg => {}
+                Ok(_) => panic!("{} does not satisfy the bound of type parameter `{}` of `{}`", type_name(arg), param.0, ident),
+                Err(e) => panic!("{} of `{}`", e, ident)
+            }
+        }
+        let bindings: HashMap<_, _> = type_params.iter().map(|p| p.0.clone()).zip(type_args.iter().cloned()).collect();
+        if let BaseExpr::FuncDecl { para_in, ranges, is_par, launch, params, ret, body, .. } = func.clone() {
+            let params: Vec<_> = params.into_iter().map(|(n, ty)| (n, substitute(&ty, &bindings))).collect();
+            let ret = substitute(&ret, &bindings);
+            let ty = TyName::Arrow(Box::new(TyName::Tuple(params.iter().map(|p| p.1.clone()).collect())), Box::new(ret.clone()));
+            let decl = BaseExpr::FuncDecl { ident: name.unwrap_or(mangled.clone()), para_in, ranges, is_par, launch, params, ret, body: substitute_body(body, &bindings) };
+            let (func_ref, _) = llvm_declare_func(decl.clone(), context, module, builder);
+            self.instances.borrow_mut().insert(mangled, (func_ref, ty.clone()));
+            self.pending.borrow_mut().push(((decl, ty.clone()), func_ref));
+            return (func_ref, ty);
+        }
+        unreachable!()
+    }
+
+    pub fn check(&self, module_decl: &HashMap<String, Vec<(LLVMValueRef, TyName)>>) {
+        for (ident, decls) in self.decls.iter() {
+            for (type_params, func) in decls.iter() {
+                for type_args in self.bound_combinations(type_params) {
+                    let bindings: HashMap<_, _> = type_params.iter().map(|p| p.0.clone()).zip(type_args.iter().cloned()).collect();
+                    // an opaque parameter provides what the interfaces of its bound declare
+                    let mut assumed = HashMap::<String, Vec<(LLVMValueRef, TyName)>>::new();
+                    for (param, arg) in type_params.iter().zip(type_args.iter()) {
+                        for interface in self.split_bound(&param.1).0 {
+                            for (name, sig) in self.interface_methods(&interface, arg) {
+                                assumed.entry(name).or_insert(vec![]).push((null_mut(), sig));
+                            }
+                            self.assumed.borrow_mut().push((interface, arg.clone()));
+                        }
+                    }
+                    let checked = self.check_body(func, &bindings, module_decl, &assumed);
+                    self.assumed.borrow_mut().clear();
+                    if let Err(e) = checked {
+                        let with = type_params.iter().zip(type_args.iter()).map(|(param, arg)| if *arg == TyName::NameBind(param.0.clone()) {
+                            format!("{} opaque", param.0)
+                        } else {
+                            format!("{} = {}", param.0, type_name(arg))
+                        }).collect::<Vec<_>>().join(", ");
+                        panic!("{}\n  in generic `{}` with {}", e, ident, with);
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_body(&self, func: &BaseExpr, bindings: &HashMap<String, TyName>, module_decl: &HashMap<String, Vec<(LLVMValueRef, TyName)>>, assumed: &HashMap<String, Vec<(LLVMValueRef, TyName)>>) -> Result<(), String> {
+        if let BaseExpr::FuncDecl { para_in, is_par, params, ret, body, .. } = func {
+            let mut params: Vec<_> = params.iter().map(|(n, ty)| (n.clone(), substitute(ty, bindings))).collect();
+            if *is_par {
+                params = shape_kernel_arrays(params);
+            }
+            params.extend(hidden_params(&params));
+            let mut env: HashMap<_, _> = params.into_iter().collect();
+            for par in para_in.iter() {
+                env.insert(par.clone(), TyName::NameBind(String::from("i64")));
+            }
+            let ret = substitute(ret, bindings);
+            for expr in substitute_body(body.clone(), bindings).iter() {
+                self.check_expr(&expr.0, &mut env, &ret, module_decl, assumed)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Mirrors the typing done during code generation, without generating anything
+    fn check_expr(&self, expr: &BaseExpr, env: &mut HashMap<String, TyName>, ret: &TyName, module_decl: &HashMap<String, Vec<(LLVMValueRef, TyName)>>, assumed: &HashMap<String, Vec<(LLVMValueRef, TyName)>>) -> Result<TyName, String> {
+        match expr {
+            BaseExpr::ConstantFloat(_) => Ok(TyName::NameBind(String::from("f64"))),
+            BaseExpr::ConstantInt(_) => Ok(TyName::NameBind(String::from("i64"))),
+            BaseExpr::Ident(id) => env.get(id).cloned().or_else(|| module_const(module_decl, &id[..]).map(|c| c.1))
+                .ok_or_else(|| format!("Could not find variable `{}` in current context !", id)),
+            BaseExpr::LetDecl(id, _, value) => {
+                let ty = self.check_expr(&value.0, env, ret, module_decl, assumed)?;
+                env.insert(id.clone(), ty.clone());
+                Ok(ty)
+            }
+            BaseExpr::SharedDecl(id, ty) => {
+                let ty = if is_mutable(ty) { ty.clone() } else { TyName::MutBind(Box::new(ty.clone())) };
+                env.insert(id.clone(), ty.clone());
+                Ok(ty)
+            }
+            BaseExpr::Return(value) => {
+                let ty = self.check_expr(&value.0, env, ret, module_decl, assumed)?;
+                if subtype_check(&ty, ret, &mut 0) { Ok(ty) } else { Err(format!("Cannot return {:?} from a function returning {:?}", ty, ret)) }
+            }
+            BaseExpr::IfExpr(cond, body, next) | BaseExpr::Else(Some(cond), body, next) => {
+                let ty = self.check_expr(&cond.0, env, ret, module_decl, assumed)?;
+                if ty != TyName::NameBind(String::from("bool")) {
+                    return Err(format!("Condition of `if` must be a bool, {} given", type_name(&ty)));
+                }
+                let mut scope = env.clone();
+                for expr in body.iter() {
+                    self.check_expr(&expr.0, &mut scope, ret, module_decl, assumed)?;
+                }
+                match next {
+                    Some(next) => self.check_expr(&next.0, env, ret, module_decl, assumed),
+                    None => Ok(TyName::Unit)
+                }
+            }
+            BaseExpr::Else(None, body, _) => {
+                let mut scope = env.clone();
+                for expr in body.iter() {
+                    self.check_expr(&expr.0, &mut scope, ret, module_decl, assumed)?;
+                }
+                Ok(TyName::Unit)
+            }
+            BaseExpr::Member(obj, field) => {
+                let ty = self.check_expr(&obj.0, env, ret, module_decl, assumed)?;
+                if let TyName::Record(name, fields) = strip_mut(&ty) {
+                    return fields.iter().find(|f| f.0.eq(field)).map(|f| f.1.clone()).ok_or_else(|| format!("Record `{}` has no field `{}`", name, field));
+                }
+                let (elem, lanes) = vector_type_of(&ty).ok_or_else(|| format!("Type {:?} has no member `{}`", ty, field))?;
+                if field.is_empty() || field.chars().any(|c| "xyzw".find(c).map(|i| i as u32 >= lanes).unwrap_or(true)) {
+                    return Err(format!("Invalid swizzle `.{}` of `{}x{}`", field, elem, lanes));
+                }
+                Ok(TyName::NameBind(if field.len() == 1 { elem } else { format!("{}x{}", elem, field.len()) }))
+            }
+            BaseExpr::Reduce { index, range, value, op, target, loc } => {
+                let range = self.check_expr(&range.0, env, ret, module_decl, assumed)?;
+                let target = target.iter().map(|arg| self.check_expr(&arg.0, env, ret, module_decl, assumed)).collect::<Result<Vec<_>, _>>()?;
+                let mut scope = env.clone();
+                scope.insert(index.clone(), TyName::NameBind(String::from("i64")));
+                let value = self.check_expr(&value.0, &mut scope, ret, module_decl, assumed)?;
+                check_reduce(&op[..], &range, &value, &target).map_err(|e| format!("{}: {}", loc, e))
+            }
+            BaseExpr::FuncCall(ident, args, loc) => {
+                let count = if ident.starts_with("@") { split_options(&ident[..], args).map_err(|e| format!("{}: {}", loc, e))?.0 } else { args.len() };
+                let mut types = args[..count].iter().map(|arg| self.check_expr(&arg.0, env, ret, module_decl, assumed)).collect::<Result<Vec<_>, _>>()?;
+                adopt_literals(&ident[..], &args[..count], &mut types);
+                let args = types;
+                match &ident[..] {
+                    _ if ident.starts_with("@") => check_intrinsic(&ident[..], &args).map_err(|e| format!("{}: {}", loc, e)),
+                    _ if vector_type(&ident[..]).is_some() => Ok(TyName::NameBind(ident.clone())),
+                    _ if is_builtin_compare(&ident[..], &args) => compare_type(&args[0], &args[1])
+                        .map(|_| TyName::NameBind(String::from("bool")))
+                        .ok_or_else(|| format!("{}: Cannot compare {:?} with {:?}", loc, args[0], args[1])),
+                    "add" | "subtract" | "multiply" | "divide" if args.len() == 2 && args.iter().any(|a| vector_type_of(a).is_some()) => {
+                        Ok(args.iter().find(|a| vector_type_of(a).is_some()).unwrap().clone())
+                    }
+                    _ => match module_decl.get(&ident[..]).and_then(|decls| select_overload(decls, &args))
+                        .or_else(|| assumed.get(&ident[..]).and_then(|decls| select_overload(decls, &args))) {
+                        Some(func) => Ok(func.2),
+                        None => self.infer(&ident[..], &args).map(|v| v.2).map_err(|e| format!("{}: {}", loc, e))
+                    }
+                }
+            }
+            _ => Ok(TyName::Unit)
+        }
+    }
+}
@@ -0,0 +1,541 @@
+use crate::parser::*;
+use crate::llvm_gen::{element_type, shape_of, is_mutable, subtype_check};
+use crate::generics::type_name;
+use std::collections::HashMap;
+
+// An `@` intrinsic as seen by the type checker, code generation lives in `build_intrinsics`
+pub(crate) struct Intrinsic {
+    pub name: &'static str,
+    // fewest and most arguments, `None` when the indices make it variadic
+    pub arity: (usize, Option<usize>),
+    pub signature: &'static str,
+    pub doc: &'static str,
+    // types the arguments, once their number is known to be right
+    check: fn(&[TyName]) -> Result<TyName, String>,
+    // names that may trail the arguments, e.g. the memory ordering of atomics
+    pub options: &'static [&'static str],
+    // arguments following the indices
+    pub operands: usize,
+}
+
+pub(crate) const ORDERINGS: &[&str] = &["relaxed", "acquire", "release", "acq_rel", "seq_cst"];
+pub(crate) const SCOPES: &[&str] = &["block", "device", "system"];
+const ATOMIC_OPTIONS: &[&str] = &["relaxed", "acquire", "release", "acq_rel", "seq_cst", "block", "device", "system"];
+
+pub(crate) const INTRINSICS: &[Intrinsic] = &[
+    Intrinsic {
+        name: "@load",
+        arity: (2, None),
+        signature: "@load(a: [T; n..], i: int..) -> T",
+        doc: "Reads the element of `a` at one index per dimension",
+        check: check_load,
+        options: &[],
+        operands: 0,
+    },
+    Intrinsic {
+        name: "@store",
+        arity: (3, None),
+        signature: "@store(a: mut [T; n..], i: int.., v: T)",
+        doc: "Writes `v` to the element of `a` at one index per dimension",
+        check: check_store,
+        options: &[],
+        operands: 1,
+    },
+    Intrinsic {
+        name: "@len",
+        arity: (1, Some(2)),
+        signature: "@len(a: [T; n..], d: int) -> i64",
+        doc: "Number of elements of `a`, or its extent along the constant dimension `d`",
+        check: check_len,
+        options: &[],
+        operands: 0,
+    },
+    Intrinsic {
+        name: "@sync",
+        arity: (0, Some(0)),
+        signature: "@sync()",
+        doc: "Waits for every thread of the block, writes made before it are visible to the whole block after it",
+        check: check_none,
+        options: &[],
+        operands: 0,
+    },
+    Intrinsic {
+        name: "@atomic_add",
+        arity: (3, None),
+        signature: "@atomic_add(a: mut [T; n..], i: int.., v: T, ordering?, scope?) -> T",
+        doc: "Adds `v` to the element of `a` and returns its previous value, `T` is i32, i64, f32 or f64",
+        check: check_atomic_arith,
+        options: ATOMIC_OPTIONS,
+        operands: 1,
+    },
+    Intrinsic {
+        name: "@atomic_min",
+        arity: (3, None),
+        signature: "@atomic_min(a: mut [T; n..], i: int.., v: T, ordering?, scope?) -> T",
+        doc: "Keeps the smaller of `v` and the element of `a` and returns its previous value",
+        check: check_atomic_arith,
+        options: ATOMIC_OPTIONS,
+        operands: 1,
+    },
+    Intrinsic {
+        name: "@atomic_max",
+        arity: (3, None),
+        signature: "@atomic_max(a: mut [T; n..], i: int.., v: T, ordering?, scope?) -> T",
+        doc: "Keeps the larger of `v` and the element of `a` and returns its previous value",
+        check: check_atomic_arith,
+        options: ATOMIC_OPTIONS,
+        operands: 1,
+    },
+    Intrinsic {
+        name: "@atomic_xchg",
+        arity: (3, None),
+        signature: "@atomic_xchg(a: mut [T; n..], i: int.., v: T, ordering?, scope?) -> T",
+        doc: "Replaces the element of `a` by `v` and returns its previous value",
+        check: check_atomic_arith,
+        options: ATOMIC_OPTIONS,
+        operands: 1,
+    },
+    Intrinsic {
+        name: "@atomic_cas",
+        arity: (4, None),
+        signature: "@atomic_cas(a: mut [T; n..], i: int.., expected: T, v: T, ordering?, scope?) -> T",
+        doc: "Replaces the element of `a` by `v` if it equals `expected`, returns its previous value either way",
+        check: check_atomic_cas,
+        options: ATOMIC_OPTIONS,
+        operands: 2,
+    },
+    Intrinsic {
+        name: "@atomic_and",
+        arity: (3, None),
+        signature: "@atomic_and(a: mut [T; n..], i: int.., v: T, ordering?, scope?) -> T",
+        doc: "Bitwise and of `v` into the element of `a`, returns its previous value, `T` is i32 or i64",
+        check: check_atomic_bits,
+        options: ATOMIC_OPTIONS,
+        operands: 1,
+    },
+    Intrinsic {
+        name: "@atomic_or",
+        arity: (3, None),
+        signature: "@atomic_or(a: mut [T; n..], i: int.., v: T, ordering?, scope?) -> T",
+        doc: "Bitwise or of `v` into the element of `a`, returns its previous value, `T` is i32 or i64",
+        check: check_atomic_bits,
+        options: ATOMIC_OPTIONS,
+        operands: 1,
+    },
+    Intrinsic {
+        name: "@atomic_xor",
+        arity: (3, None),
+        signature: "@atomic_xor(a: mut [T; n..], i: int.., v: T, ordering?, scope?) -> T",
+        doc: "Bitwise xor of `v` into the element of `a`, returns its previous value, `T` is i32 or i64",
+        check: check_atomic_bits,
+        options: ATOMIC_OPTIONS,
+        operands: 1,
+    },
+    Intrinsic {
+        name: "@thread_idx",
+        arity: (1, Some(1)),
+        signature: "@thread_idx(d: int) -> i64",
+        doc: "Index of the thread within its block along the constant dimension `d`",
+        check: check_dim,
+        options: &[],
+        operands: 0,
+    },
+    Intrinsic {
+        name: "@block_idx",
+        arity: (1, Some(1)),
+        signature: "@block_idx(d: int) -> i64",
+        doc: "Index of the block within the grid along the constant dimension `d`",
+        check: check_dim,
+        options: &[],
+        operands: 0,
+    },
+    Intrinsic {
+        name: "@block_dim",
+        arity: (1, Some(1)),
+        signature: "@block_dim(d: int) -> i64",
+        doc: "Number of threads in a block along the constant dimension `d`",
+        check: check_dim,
+        options: &[],
+        operands: 0,
+    },
+    Intrinsic {
+        name: "@grid_dim",
+        arity: (1, Some(1)),
+        signature: "@grid_dim(d: int) -> i64",
+        doc: "Number of blocks in the grid along the constant dimension `d`",
+        check: check_dim,
+        options: &[],
+        operands: 0,
+    },
+    Intrinsic {
+        name: "@lane_id",
+        arity: (0, Some(0)),
+        signature: "@lane_id() -> i64",
+        doc: "Index of the thread within its warp of 32 lanes",
+        check: check_lane,
+        options: &[],
+        operands: 0,
+    },
+    Intrinsic {
+        name: "@shfl_up",
+        arity: (2, Some(2)),
+        signature: "@shfl_up(v: T, d: int) -> T",
+        doc: "Value of `v` in the lane `d` below, lanes without one keep their own, `T` is i32, i64, f32 or f64",
+        check: check_shuffle,
+        options: &[],
+        operands: 0,
+    },
+    Intrinsic {
+        name: "@shfl_down",
+        arity: (2, Some(2)),
+        signature: "@shfl_down(v: T, d: int) -> T",
+        doc: "Value of `v` in the lane `d` above, lanes without one keep their own",
+        check: check_shuffle,
+        options: &[],
+        operands: 0,
+    },
+    Intrinsic {
+        name: "@shfl_xor",
+        arity: (2, Some(2)),
+        signature: "@shfl_xor(v: T, m: int) -> T",
+        doc: "Value of `v` in the lane whose index differs by the bits of `m`",
+        check: check_shuffle,
+        options: &[],
+        operands: 0,
+    },
+    Intrinsic {
+        name: "@shfl_idx",
+        arity: (2, Some(2)),
+        signature: "@shfl_idx(v: T, l: int) -> T",
+        doc: "Value of `v` in lane `l`",
+        check: check_shuffle,
+        options: &[],
+        operands: 0,
+    },
+    Intrinsic {
+        name: "@vote_any",
+        arity: (1, Some(1)),
+        signature: "@vote_any(p: bool) -> bool",
+        doc: "Whether `p` holds in any lane of the warp",
+        check: check_vote,
+        options: &[],
+        operands: 0,
+    },
+    Intrinsic {
+        name: "@vote_all",
+        arity: (1, Some(1)),
+        signature: "@vote_all(p: bool) -> bool",
+        doc: "Whether `p` holds in every lane of the warp",
+        check: check_vote,
+        options: &[],
+        operands: 0,
+    },
+    Intrinsic {
+        name: "@ballot",
+        arity: (1, Some(1)),
+        signature: "@ballot(p: bool) -> i32",
+        doc: "Mask with bit `k` set when `p` holds in lane `k`",
+        check: check_ballot,
+        options: &[],
+        operands: 0,
+    },
+];
+
+fn is_integer(ty: &TyName) -> bool {
+    match ty {
+        TyName::NameBind(name) => ["i8", "i16", "i32", "i64"].contains(&&name[..]),
+        _ => false
+    }
+}
+
+fn check_indices(array: &TyName, indices: &[TyName]) -> Result<TyName, String> {
+    let elem = element_type(array).ok_or_else(|| format!("expects an array, {} given", type_name(array)))?;
+    let rank = shape_of(array).map(|s| s.0.len()).unwrap_or(1);
+    if indices.len() != rank {
+        return Err(format!("expects {} {} for an array of type {}, {} given", rank, if rank == 1 { "index" } else { "indices" }, type_name(array), indices.len()));
+    }
+    match indices.iter().position(|i| !is_integer(i)) {
+        Some(k) => Err(format!("expects integer indices, index {} is {}", k, type_name(&indices[k]))),
+        None => Ok(elem)
+    }
+}
+
+fn check_load(args: &[TyName]) -> Result<TyName, String> {
+    check_indices(&args[0], &args[1..])
+}
+
+fn check_store(args: &[TyName]) -> Result<TyName, String> {
+    let value = &args[args.len() - 1];
+    let elem = check_indices(&args[0], &args[1..args.len() - 1])?;
+    if !is_mutable(&args[0]) {
+        return Err(format!("cannot store into immutable array of type {}, declare it as `mut`", type_name(&args[0])));
+    }
+    if !subtype_check(value, &elem, &mut 0) {
+        return Err(format!("cannot store {} into an array of {}", type_name(value), type_name(&elem)));
+    }
+    Ok(TyName::Unit)
+}
+
+fn check_len(args: &[TyName]) -> Result<TyName, String> {
+    if shape_of(&args[0]).is_none() {
+        return Err(format!("length of array of type {} is unknown, declare its shape as `[T; n]`", type_name(&args[0])));
+    }
+    if args.len() > 1 && !is_integer(&args[1]) {
+        return Err(format!("expects an integer dimension, {} given", type_name(&args[1])));
+    }
+    Ok(TyName::NameBind(String::from("i64")))
+}
+
+// Atomics operate on the 32 and 64 bit elements of a mutable array, in global or shared memory
+fn check_atomic(args: &[TyName], operands: usize, types: &[&str]) -> Result<TyName, String> {
+    let elem = check_indices(&args[0], &args[1..args.len() - operands])?;
+    if !is_mutable(&args[0]) {
+        return Err(format!("cannot update immutable array of type {}, declare it as `mut`", type_name(&args[0])));
+    }
+    match &elem {
+        TyName::NameBind(name) if types.contains(&&name[..]) => {}
+        _ => return Err(format!("is not available on elements of type {}, only on {}", type_name(&elem), types.join(", ")))
+    }
+    if let Some(value) = args[args.len() - operands..].iter().find(|v| !subtype_check(v, &elem, &mut 0)) {
+        return Err(format!("cannot combine {} with an element of type {}", type_name(value), type_name(&elem)));
+    }
+    Ok(elem)
+}
+
+fn check_atomic_arith(args: &[TyName]) -> Result<TyName, String> {
+    check_atomic(args, 1, &["i32", "i64", "f32", "f64"])
+}
+
+fn check_atomic_bits(args: &[TyName]) -> Result<TyName, String> {
+    check_atomic(args, 1, &["i32", "i64"])
+}
+
+fn check_atomic_cas(args: &[TyName]) -> Result<TyName, String> {
+    check_atomic(args, 2, &["i32", "i64", "f32", "f64"])
+}
+
+fn check_lane(_: &[TyName]) -> Result<TyName, String> {
+    Ok(TyName::NameBind(String::from("i64")))
+}
+
+// Lanes exchange 32 bits at a time, 64 bit values are shuffled as two halves
+fn check_shuffle(args: &[TyName]) -> Result<TyName, String> {
+    let value = match &args[0] { TyName::MutBind(inner) => &**inner, ty => ty };
+    match value {
+        TyName::NameBind(name) if ["i32", "i64", "f32", "f64"].contains(&&name[..]) => {}
+        _ => return Err(format!("cannot exchange values of type {}, only i32, i64, f32 and f64", type_name(value)))
+    }
+    if !is_integer(&args[1]) {
+        return Err(format!("expects an integer lane, {} given", type_name(&args[1])));
+    }
+    Ok(value.clone())
+}
+
+fn check_predicate(args: &[TyName]) -> Result<(), String> {
+    match &args[0] {
+        TyName::NameBind(name) if name.eq("bool") => Ok(()),
+        ty => Err(format!("expects a bool, {} given", type_name(ty)))
+    }
+}
+
+fn check_vote(args: &[TyName]) -> Result<TyName, String> {
+    check_predicate(args).map(|_| TyName::NameBind(String::from("bool")))
+}
+
+fn check_ballot(args: &[TyName]) -> Result<TyName, String> {
+    check_predicate(args).map(|_| TyName::NameBind(String::from("i32")))
+}
+
+fn check_none(_: &[TyName]) -> Result<TyName, String> {
+    Ok(TyName::Unit)
+}
+
+fn check_dim(args: &[TyName]) -> Result<TyName, String> {
+    if !is_integer(&args[0]) {
+        return Err(format!("expects an integer dimension, {} given", type_name(&args[0])));
+    }
+    Ok(TyName::NameBind(String::from("i64")))
+}
+
+pub(crate) fn lookup(name: &str) -> Option<&'static Intrinsic> {
+    INTRINSICS.iter().find(|i| i.name.eq(name))
+}
+
+// Separates the trailing option names of a call from its arguments, at most one ordering and one scope
+pub(crate) fn split_options(name: &str, args: &[TypedExpr]) -> Result<(usize, Vec<String>), String> {
+    let allowed = lookup(name).map(|i| i.options).unwrap_or(&[]);
+    let mut count = args.len();
+    let mut options: Vec<String> = vec![];
+    while let Some((BaseExpr::Ident(id), _)) = args[..count].last() {
+        if !allowed.contains(&&id[..]) {
+            break;
+        }
+        let kind = if ORDERINGS.contains(&&id[..]) { ORDERINGS } else { SCOPES };
+        if let Some(other) = options.iter().find(|o| kind.contains(&&o[..])) {
+            return Err(format!("`{}` is given both `{}` and `{}`, only one {} is allowed", name, id, other, if kind == ORDERINGS { "ordering" } else { "scope" }));
+        }
+        options.insert(0, id.clone());
+        count -= 1;
+    }
+    Ok((count, options))
+}
+
+// Integer literals are i64, those given as the value of an element take its type when they fit
+pub(crate) fn adopt_literals(name: &str, args: &[TypedExpr], types: &mut [TyName]) {
+    let operands = lookup(name).map(|i| i.operands).unwrap_or(0);
+    if operands == 0 || types.len() <= operands {
+        return;
+    }
+    let bits = match element_type(&types[0]) {
+        Some(TyName::NameBind(elem)) => match &elem[..] { "i8" => 8, "i16" => 16, "i32" => 32, _ => return },
+        _ => return
+    };
+    for k in types.len() - operands..types.len() {
+        if let BaseExpr::ConstantInt(v) = args[k].0 {
+            if v >= -(1 << (bits - 1)) && v < 1 << (bits - 1) {
+                types[k] = element_type(&types[0]).unwrap();
+            }
+        }
+    }
+}
+
+// The index arguments of an array access, without the array, the operands and the options
+pub(crate) fn access_indices<'a>(name: &str, args: &'a [TypedExpr]) -> Option<&'a [TypedExpr]> {
+    let intrinsic = lookup(name)?;
+    if !(name.eq("@load") || name.eq("@store") || name.starts_with("@atomic_")) {
+        return None;
+    }
+    let count = split_options(name, args).map(|s| s.0).unwrap_or(args.len());
+    Some(&args[1.min(count)..count.max(1 + intrinsic.operands) - intrinsic.operands])
+}
+
+// The operations `reduce` combines with, each has an atomic combining the totals of the blocks
+pub(crate) const REDUCE_OPS: &[&str] = &["add", "min", "max"];
+
+const REDUCE_SIGNATURE: &str = "reduce<i in n>(value, op, out, k..)";
+const REDUCE_DOC: &str = "Combines `value` over `0..n` with `op`, it evaluates to the total of the block of the calling thread only, \
+                          the blocks combine their totals into `out[k..]` when it is given";
+
+// Types `reduce<i in n>(value, op, out, k..)`, given the types of `n`, `value` and the trailing `out, k..`
+pub(crate) fn check_reduce(op: &str, range: &TyName, value: &TyName, target: &[TyName]) -> Result<TyName, String> {
+    reduce_type(op, range, value, target).map_err(|e| format!("{}\n  {}  {}", e, REDUCE_SIGNATURE, REDUCE_DOC))
+}
+
+fn reduce_type(op: &str, range: &TyName, value: &TyName, target: &[TyName]) -> Result<TyName, String> {
+    if !is_integer(range) {
+        return Err(format!("`reduce` expects an integer range, {} given", type_name(range)));
+    }
+    if !REDUCE_OPS.contains(&op) {
+        return Err(format!("`reduce` cannot combine with `{}`, only with {}", op, REDUCE_OPS.join(", ")));
+    }
+    let value = match value { TyName::MutBind(inner) => &**inner, ty => ty };
+    match value {
+        TyName::NameBind(name) if ["i32", "i64", "f32", "f64"].contains(&&name[..]) => {}
+        _ => return Err(format!("`reduce` cannot combine values of type {}, only i32, i64, f32 and f64", type_name(value)))
+    }
+    if !target.is_empty() {
+        let mut args = target.to_vec();
+        args.push(value.clone());
+        check_intrinsic(&format!("@atomic_{}", op), &args).map_err(|e| format!("`reduce` cannot combine the totals of the blocks, {}", e))?;
+    }
+    Ok(value.clone())
+}
+
+// Types a call to an `@` intrinsic, the error carries the expected signature
+pub(crate) fn check_intrinsic(name: &str, args: &[TyName]) -> Result<TyName, String> {
+    let intrinsic = lookup(name).ok_or_else(|| {
+        let known: Vec<_> = INTRINSICS.iter().map(|i| format!("  {}  {}", i.signature, i.doc)).collect();
+        format!("Unknown intrinsic `{}`, available intrinsics are\n{}", name, known.join("\n"))
+    })?;
+    let (min, max) = intrinsic.arity;
+    if args.len() < min || max.map(|max| args.len() > max).unwrap_or(false) {
+        let expected = match max {
+            Some(max) if max == min => format!("{}", min),
+            Some(max) => format!("{} to {}", min, max),
+            None => format!("at least {}", min),
+        };
+        return Err(format!("`{}` takes {} arguments, {} given\n  {}  {}", name, expected, args.len(), intrinsic.signature, intrinsic.doc));
+    }
+    (intrinsic.check)(args).map_err(|e| format!("`{}` {}\n  {}  {}", name, e, intrinsic.signature, intrinsic.doc))
+}
+
+// The arrays a body may write into, constants are only ever written by the host
+#[derive(Clone)]
+enum Array {
+    Memory(TyName),
+    Constant,
+}
+
+// Writes through arrays not declared `mut` or into constants are rejected before code generation. The
+// arrays tracked are the constants, the parameters and the `let` bindings naming them, shared arrays
+// are always writable
+pub(crate) fn check_stores(func: &BaseExpr, consts: &[(String, TyName)]) -> Vec<String> {
+    let mut errors = vec![];
+    if let BaseExpr::FuncDecl { params, body, .. } = func {
+        let mut arrays: HashMap<String, Array> = consts.iter().map(|c| (c.0.clone(), Array::Constant)).collect();
+        arrays.extend(params.iter().filter(|p| element_type(&p.1).is_some()).map(|p| (p.0.clone(), Array::Memory(p.1.clone()))));
+        visit_stores(body, &mut arrays, &mut errors);
+    }
+    errors
+}
+
+fn visit_stores(body: &Vec<TypedExpr>, arrays: &mut HashMap<String, Array>, errors: &mut Vec<String>) {
+    for expr in body.iter() {
+        visit_store(&expr.0, arrays, errors);
+    }
+}
+
+fn visit_store(expr: &BaseExpr, arrays: &mut HashMap<String, Array>, errors: &mut Vec<String>) {
+    let mut check_target = |writer: &str, target: Option<&TypedExpr>, loc: &SrcLoc| {
+        if let Some((BaseExpr::Ident(array), _)) = target {
+            match arrays.get(array) {
+                Some(Array::Memory(ty)) if !is_mutable(ty) =>
+                    errors.push(format!("{}: `{}` cannot write into immutable array `{}` of type {}, declare it as `mut`", loc, writer, array, type_name(ty))),
+                Some(Array::Constant) =>
+                    errors.push(format!("{}: `{}` cannot write into `{}` in constant memory, it is only written by the host before launching", loc, writer, array)),
+                _ => {}
+            }
+        }
+    };
+    match expr {
+        BaseExpr::FuncCall(ident, args, loc) => {
+            if ident.eq("@store") || ident.starts_with("@atomic_") {
+                check_target(&ident[..], args.first(), loc);
+            }
+            for arg in args.iter() {
+                visit_store(&arg.0, arrays, errors);
+            }
+        }
+        BaseExpr::Reduce { range, value, target, loc, .. } => {
+            if !target.is_empty() {
+                check_target("reduce", target.first(), loc);
+            }
+            visit_store(&range.0, arrays, errors);
+            visit_store(&value.0, arrays, errors);
+        }
+        BaseExpr::LetDecl(id, _, value) => {
+            visit_store(&value.0, arrays, errors);
+            let aliased = match &value.0 {
+                BaseExpr::Ident(src) => arrays.get(src).cloned(),
+                _ => None
+            };
+            match aliased {
+                Some(ty) => arrays.insert(id.clone(), ty),
+                None => arrays.remove(id)
+            };
+        }
+        BaseExpr::SharedDecl(id, _) => {
+            arrays.remove(id);
+        }
+        BaseExpr::Assign(_, value) | BaseExpr::Return(value) | BaseExpr::Member(value, _) => visit_store(&value.0, arrays, errors),
+        BaseExpr::IfExpr(cond, body, next) | BaseExpr::Else(Some(cond), body, next) => {
+            visit_store(&cond.0, arrays, errors);
+            visit_stores(body, &mut arrays.clone(), errors);
+            if let Some(next) = next {
+                visit_store(&next.0, &mut arrays.clone(), errors);
+            }
+        }
+        BaseExpr::Else(None, body, _) => visit_stores(body, &mut arrays.clone(), errors),
+        _ => {}
+    }
+}
@@ -1,18 +1,159 @@
 use llvm::prelude::*;
 use llvm::core::*;
 use std::ffi::{CString, CStr};
+use std::ptr::null_mut;
+
+// The GPU a module is compiled for, its triple is read back when declaring kernels
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Target {
+    Nvptx,
+    Amdgcn,
+}
+
+impl Target {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nvptx" | "nvptx64" => Some(Target::Nvptx),
+            "amdgcn" => Some(Target::Amdgcn),
+            _ => None
+        }
+    }
+
+    pub fn triple(&self) -> &'static str {
+        match self {
+            Target::Nvptx => "nvptx64-nvidia-cuda",
+            Target::Amdgcn => "amdgcn-amd-amdhsa",
+        }
+    }
+
+    pub fn data_layout(&self) -> &'static str {
+        match self {
+            Target::Nvptx => "e-i64:64-i128:128-v16:16-v32:32-n16:32:64",
+            Target::Amdgcn => "e-p:64:64-p1:64:64-p2:32:32-p3:32:32-p4:64:64-p5:32:32-p6:32:32-i64:64-v16:16-v24:32-v32:32-v48:64-v96:128-v192:256-v256:256-v512:512-v1024:1024-v2048:2048-n32:64-S32-A5-G1-ni:7",
+        }
+    }
+
+    // Half instructions came with sm_53 on NVIDIA and with gfx8 on AMD
+    pub fn has_half_arith(&self, cpu: &str) -> bool {
+        let version = match self {
+            Target::Nvptx if cpu.starts_with("sm_") => cpu[3..].chars().take_while(|c| c.is_ascii_digit()).collect::<String>(),
+            // gfx names end in a two-character stepping, gfx906 and gfx90a are major 9, gfx1030 is 10
+            Target::Amdgcn if cpu.starts_with("gfx") && cpu.len() > 5 => cpu[3..cpu.len() - 2].to_string(),
+            _ => return false
+        };
+        let min = if *self == Target::Nvptx { 53 } else { 8 };
+        version.parse::<u32>().map(|v| v >= min).unwrap_or(false)
+    }
+
+    pub fn of_module(module: LLVMModuleRef) -> Self {
+        let triple = unsafe { CStr::from_ptr(LLVMGetTarget(module)) };
+        if triple.to_bytes().starts_with(b"amdgcn") { Target::Amdgcn } else { Target::Nvptx }
+    }
+}
+
+// Block and grid sizes come from the HSA dispatch packet on amdgcn, the packet holds the
+// workgroup size as i16 at offsets 4, 6, 8 and the grid size in work-items as i32 at 12, 16, 20.
+// The barrier is fenced on both sides so that it orders shared memory like bar.sync does
+pub const AMDGCN_SUPPORT_IR: &str = "declare i8 addrspace(4)* @llvm.amdgcn.dispatch.ptr()
+
+declare void @llvm.amdgcn.s.barrier()
+
+define linkonce_odr i32 @ruda.amdgcn.group_size(i64 %offset) {
+  %packet = call i8 addrspace(4)* @llvm.amdgcn.dispatch.ptr()
+  %field = getelementptr inbounds i8, i8 addrspace(4)* %packet, i64 %offset
+  %ptr = bitcast i8 addrspace(4)* %field to i16 addrspace(4)*
+  %size = load i16, i16 addrspace(4)* %ptr, align 2
+  %ext = zext i16 %size to i32
+  ret i32 %ext
+}
+
+define linkonce_odr i32 @ruda.amdgcn.grid_groups(i64 %offset, i64 %group_offset) {
+  %packet = call i8 addrspace(4)* @llvm.amdgcn.dispatch.ptr()
+  %field = getelementptr inbounds i8, i8 addrspace(4)* %packet, i64 %offset
+  %ptr = bitcast i8 addrspace(4)* %field to i32 addrspace(4)*
+  %items = load i32, i32 addrspace(4)* %ptr, align 4
+  %group = call i32 @ruda.amdgcn.group_size(i64 %group_offset)
+  %round = add i32 %items, %group
+  %up = sub i32 %round, 1
+  %groups = udiv i32 %up, %group
+  ret i32 %groups
+}
+
+define linkonce_odr i32 @ruda.amdgcn.block_dim.x() {
+  %size = call i32 @ruda.amdgcn.group_size(i64 4)
+  ret i32 %size
+}
+
+define linkonce_odr i32 @ruda.amdgcn.block_dim.y() {
+  %size = call i32 @ruda.amdgcn.group_size(i64 6)
+  ret i32 %size
+}
+
+define linkonce_odr i32 @ruda.amdgcn.block_dim.z() {
+  %size = call i32 @ruda.amdgcn.group_size(i64 8)
+  ret i32 %size
+}
+
+define linkonce_odr i32 @ruda.amdgcn.grid_dim.x() {
+  %groups = call i32 @ruda.amdgcn.grid_groups(i64 12, i64 4)
+  ret i32 %groups
+}
+
+define linkonce_odr i32 @ruda.amdgcn.grid_dim.y() {
+  %groups = call i32 @ruda.amdgcn.grid_groups(i64 16, i64 6)
+  ret i32 %groups
+}
+
+define linkonce_odr i32 @ruda.amdgcn.grid_dim.z() {
+  %groups = call i32 @ruda.amdgcn.grid_groups(i64 20, i64 8)
+  ret i32 %groups
+}
+
+define linkonce_odr void @ruda.amdgcn.barrier() {
+  fence syncscope(\"workgroup\") release
+  call void @llvm.amdgcn.s.barrier()
+  fence syncscope(\"workgroup\") acquire
+  ret void
+}
+
+";
 
 pub(crate) struct Context {
     pub context: LLVMContextRef,
     pub builder: LLVMBuilderRef
 }
 
+// The special registers and collectives of the target, the fields keep their NVVM names.
+// Those amdgcn has no 32-lane counterpart for are null there
 #[allow(dead_code)]
 pub struct NVIntrinsics {
     pub thread_x: LLVMValueRef,
     pub thread_y: LLVMValueRef,
     pub thread_z: LLVMValueRef,
+    pub block_x: LLVMValueRef,
+    pub block_y: LLVMValueRef,
+    pub block_z: LLVMValueRef,
+    pub block_dim_x: LLVMValueRef,
+    pub block_dim_y: LLVMValueRef,
+    pub block_dim_z: LLVMValueRef,
+    pub grid_dim_x: LLVMValueRef,
+    pub grid_dim_y: LLVMValueRef,
+    pub grid_dim_z: LLVMValueRef,
+    pub lane_id: LLVMValueRef,
+    pub shfl_up_i32: LLVMValueRef,
+    pub shfl_down_i32: LLVMValueRef,
+    pub shfl_bfly_i32: LLVMValueRef,
+    pub shfl_idx_i32: LLVMValueRef,
+    pub shfl_up_f32: LLVMValueRef,
+    pub shfl_down_f32: LLVMValueRef,
+    pub shfl_bfly_f32: LLVMValueRef,
+    pub shfl_idx_f32: LLVMValueRef,
+    pub vote_any: LLVMValueRef,
+    pub vote_all: LLVMValueRef,
+    pub ballot: LLVMValueRef,
     pub sync_thread: LLVMValueRef,
+    pub trap: LLVMValueRef,
+    pub vprintf: LLVMValueRef,
 }
 
 impl Context {
@@ -31,10 +172,15 @@ impl Context {
                 .to_owned().into_string().unwrap() }
     }
 
-    pub fn create_module(&self, name: String) -> LLVMModuleRef {
+    pub fn create_module(&self, name: String, target: Target) -> LLVMModuleRef {
         let module_name = std::mem::ManuallyDrop::new(CString::new(name).unwrap());
+        let triple = CString::new(target.triple()).unwrap();
+        let layout = CString::new(target.data_layout()).unwrap();
         unsafe{
-            LLVMModuleCreateWithNameInContext(module_name.as_ptr() as *const _, self.context)
+            let module = LLVMModuleCreateWithNameInContext(module_name.as_ptr() as *const _, self.context);
+            LLVMSetTarget(module, triple.as_ptr());
+            LLVMSetDataLayout(module, layout.as_ptr());
+            module
         }
     }
 
@@ -46,15 +192,91 @@ impl Context {
         unsafe { LLVMCreateFunctionPassManagerForModule(module) }
     }
 
+    pub fn init_intrinsics(&self, module: LLVMModuleRef, target: Target) -> NVIntrinsics {
+        match target {
+            Target::Nvptx => self.init_nvptx_intrinsics(module),
+            Target::Amdgcn => self.init_amdgcn_intrinsics(module),
+        }
+    }
+
     pub fn init_nvptx_intrinsics(&self, module: LLVMModuleRef) -> NVIntrinsics {
         unsafe {
             let type_cu_index = LLVMFunctionType(LLVMInt32TypeInContext(self.context), [].as_mut_ptr(), 0, 0);
             let type_barrier = LLVMFunctionType(LLVMVoidTypeInContext(self.context), [].as_mut_ptr(), 0, 0);
+            let type_str = LLVMPointerType(LLVMInt8TypeInContext(self.context), 0);
+            let type_vprintf = LLVMFunctionType(LLVMInt32TypeInContext(self.context), [type_str, type_str].as_mut_ptr(), 2, 0);
+            // warp collectives take the mask of participating lanes first
+            let (i1, i32, f32) = (LLVMInt1TypeInContext(self.context), LLVMInt32TypeInContext(self.context), LLVMFloatTypeInContext(self.context));
+            let type_shfl_i32 = LLVMFunctionType(i32, [i32, i32, i32, i32].as_mut_ptr(), 4, 0);
+            let type_shfl_f32 = LLVMFunctionType(f32, [i32, f32, i32, i32].as_mut_ptr(), 4, 0);
+            let type_vote = LLVMFunctionType(i1, [i32, i1].as_mut_ptr(), 2, 0);
+            let type_ballot = LLVMFunctionType(i32, [i32, i1].as_mut_ptr(), 2, 0);
             NVIntrinsics {
                 thread_x: LLVMAddFunction(module, b"llvm.nvvm.read.ptx.sreg.tid.x\0".as_ptr() as *mut _, type_cu_index),
                 thread_y: LLVMAddFunction(module, b"llvm.nvvm.read.ptx.sreg.tid.y\0".as_ptr() as *mut _, type_cu_index),
                 thread_z: LLVMAddFunction(module, b"llvm.nvvm.read.ptx.sreg.tid.z\0".as_ptr() as *mut _, type_cu_index),
+                block_x: LLVMAddFunction(module, b"llvm.nvvm.read.ptx.sreg.ctaid.x\0".as_ptr() as *mut _, type_cu_index),
+                block_y: LLVMAddFunction(module, b"llvm.nvvm.read.ptx.sreg.ctaid.y\0".as_ptr() as *mut _, type_cu_index),
+                block_z: LLVMAddFunction(module, b"llvm.nvvm.read.ptx.sreg.ctaid.z\0".as_ptr() as *mut _, type_cu_index),
+                block_dim_x: LLVMAddFunction(module, b"llvm.nvvm.read.ptx.sreg.ntid.x\0".as_ptr() as *mut _, type_cu_index),
+                block_dim_y: LLVMAddFunction(module, b"llvm.nvvm.read.ptx.sreg.ntid.y\0".as_ptr() as *mut _, type_cu_index),
+                block_dim_z: LLVMAddFunction(module, b"llvm.nvvm.read.ptx.sreg.ntid.z\0".as_ptr() as *mut _, type_cu_index),
+                grid_dim_x: LLVMAddFunction(module, b"llvm.nvvm.read.ptx.sreg.nctaid.x\0".as_ptr() as *mut _, type_cu_index),
+                grid_dim_y: LLVMAddFunction(module, b"llvm.nvvm.read.ptx.sreg.nctaid.y\0".as_ptr() as *mut _, type_cu_index),
+                grid_dim_z: LLVMAddFunction(module, b"llvm.nvvm.read.ptx.sreg.nctaid.z\0".as_ptr() as *mut _, type_cu_index),
+                lane_id: LLVMAddFunction(module, b"llvm.nvvm.read.ptx.sreg.laneid\0".as_ptr() as *mut _, type_cu_index),
+                shfl_up_i32: LLVMAddFunction(module, b"llvm.nvvm.shfl.sync.up.i32\0".as_ptr() as *mut _, type_shfl_i32),
+                shfl_down_i32: LLVMAddFunction(module, b"llvm.nvvm.shfl.sync.down.i32\0".as_ptr() as *mut _, type_shfl_i32),
+                shfl_bfly_i32: LLVMAddFunction(module, b"llvm.nvvm.shfl.sync.bfly.i32\0".as_ptr() as *mut _, type_shfl_i32),
+                shfl_idx_i32: LLVMAddFunction(module, b"llvm.nvvm.shfl.sync.idx.i32\0".as_ptr() as *mut _, type_shfl_i32),
+                shfl_up_f32: LLVMAddFunction(module, b"llvm.nvvm.shfl.sync.up.f32\0".as_ptr() as *mut _, type_shfl_f32),
+                shfl_down_f32: LLVMAddFunction(module, b"llvm.nvvm.shfl.sync.down.f32\0".as_ptr() as *mut _, type_shfl_f32),
+                shfl_bfly_f32: LLVMAddFunction(module, b"llvm.nvvm.shfl.sync.bfly.f32\0".as_ptr() as *mut _, type_shfl_f32),
+                shfl_idx_f32: LLVMAddFunction(module, b"llvm.nvvm.shfl.sync.idx.f32\0".as_ptr() as *mut _, type_shfl_f32),
+                vote_any: LLVMAddFunction(module, b"llvm.nvvm.vote.any.sync\0".as_ptr() as *mut _, type_vote),
+                vote_all: LLVMAddFunction(module, b"llvm.nvvm.vote.all.sync\0".as_ptr() as *mut _, type_vote),
+                ballot: LLVMAddFunction(module, b"llvm.nvvm.vote.ballot.sync\0".as_ptr() as *mut _, type_ballot),
                 sync_thread: LLVMAddFunction(module, b"llvm.nvvm.barrier0\0".as_ptr() as *mut _, type_barrier),
+                trap: LLVMAddFunction(module, b"llvm.trap\0".as_ptr() as *mut _, type_barrier),
+                vprintf: LLVMAddFunction(module, b"vprintf\0".as_ptr() as *mut _, type_vprintf),
+            }
+        }
+    }
+
+    // Work-item and workgroup IDs are intrinsics, the sizes and the barrier are defined by AMDGCN_SUPPORT_IR.
+    // Wavefronts have 64 lanes, so the 32-lane warp intrinsics are left out and vprintf does not exist
+    pub fn init_amdgcn_intrinsics(&self, module: LLVMModuleRef) -> NVIntrinsics {
+        unsafe {
+            let type_cu_index = LLVMFunctionType(LLVMInt32TypeInContext(self.context), [].as_mut_ptr(), 0, 0);
+            let type_barrier = LLVMFunctionType(LLVMVoidTypeInContext(self.context), [].as_mut_ptr(), 0, 0);
+            NVIntrinsics {
+                thread_x: LLVMAddFunction(module, b"llvm.amdgcn.workitem.id.x\0".as_ptr() as *mut _, type_cu_index),
+                thread_y: LLVMAddFunction(module, b"llvm.amdgcn.workitem.id.y\0".as_ptr() as *mut _, type_cu_index),
+                thread_z: LLVMAddFunction(module, b"llvm.amdgcn.workitem.id.z\0".as_ptr() as *mut _, type_cu_index),
+                block_x: LLVMAddFunction(module, b"llvm.amdgcn.workgroup.id.x\0".as_ptr() as *mut _, type_cu_index),
+                block_y: LLVMAddFunction(module, b"llvm.amdgcn.workgroup.id.y\0".as_ptr() as *mut _, type_cu_index),
+                block_z: LLVMAddFunction(module, b"llvm.amdgcn.workgroup.id.z\0".as_ptr() as *mut _, type_cu_index),
+                block_dim_x: LLVMAddFunction(module, b"ruda.amdgcn.block_dim.x\0".as_ptr() as *mut _, type_cu_index),
+                block_dim_y: LLVMAddFunction(module, b"ruda.amdgcn.block_dim.y\0".as_ptr() as *mut _, type_cu_index),
+                block_dim_z: LLVMAddFunction(module, b"ruda.amdgcn.block_dim.z\0".as_ptr() as *mut _, type_cu_index),
+                grid_dim_x: LLVMAddFunction(module, b"ruda.amdgcn.grid_dim.x\0".as_ptr() as *mut _, type_cu_index),
+                grid_dim_y: LLVMAddFunction(module, b"ruda.amdgcn.grid_dim.y\0".as_ptr() as *mut _, type_cu_index),
+                grid_dim_z: LLVMAddFunction(module, b"ruda.amdgcn.grid_dim.z\0".as_ptr() as *mut _, type_cu_index),
+                lane_id: null_mut(),
+                shfl_up_i32: null_mut(),
+                shfl_down_i32: null_mut(),
+                shfl_bfly_i32: null_mut(),
+                shfl_idx_i32: null_mut(),
+                shfl_up_f32: null_mut(),
+                shfl_down_f32: null_mut(),
+                shfl_bfly_f32: null_mut(),
+                shfl_idx_f32: null_mut(),
+                vote_any: null_mut(),
+                vote_all: null_mut(),
+                ballot: null_mut(),
+                sync_thread: LLVMAddFunction(module, b"ruda.amdgcn.barrier\0".as_ptr() as *mut _, type_barrier),
+                trap: LLVMAddFunction(module, b"llvm.trap\0".as_ptr() as *mut _, type_barrier),
+                vprintf: null_mut(),
             }
         }
     }
@@ -1,31 +1,96 @@
 use crate::parser::*;
-use crate::llvm_context::NVIntrinsics;
+use crate::llvm_context::{Context, NVIntrinsics, Target};
+use crate::generics::Generics;
+use crate::intrinsics::{adopt_literals, check_intrinsic, check_reduce, lookup, split_options, ORDERINGS};
 use std::ffi::{CString, CStr};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use llvm::analysis::LLVMVerifyFunction;
 use llvm::analysis::LLVMVerifierFailureAction::LLVMAbortProcessAction;
 use std::ptr::null_mut;
+use std::cell::Cell;
 use llvm::prelude::{LLVMContextRef, LLVMModuleRef, LLVMValueRef, LLVMBuilderRef};
 use llvm::core::*;
-use llvm::LLVMType;
+use llvm::linker::LLVMLinkModules2;
+use llvm::bit_reader::LLVMParseBitcodeInContext2;
+use llvm::{LLVMType, LLVMIntPredicate, LLVMRealPredicate, LLVMLinkage, LLVMAtomicOrdering, LLVMAtomicRMWBinOp};
 use std::ops::DerefMut;
+use std::os::raw::{c_char, c_uint, c_void};
+
+// C APIs missing from llvm-sys 38, build.rs makes sure the LLVM linked has all of them
+extern "C" {
+    fn LLVMGetEnumAttributeKindForName(name: *const c_char, len: usize) -> c_uint;
+    fn LLVMCreateEnumAttribute(context: LLVMContextRef, kind: c_uint, val: u64) -> *mut c_void;
+    fn LLVMAddAttributeAtIndex(func: LLVMValueRef, index: c_uint, attr: *mut c_void);
+    fn LLVMCreateStringAttribute(context: LLVMContextRef, key: *const c_char, key_len: c_uint, value: *const c_char, value_len: c_uint) -> *mut c_void;
+    fn LLVMGetTypeByName2(context: LLVMContextRef, name: *const c_char) -> LLVMTypeRef;
+    // the bindings predate `cmpxchg` and floating point `atomicrmw` operations
+    fn LLVMBuildAtomicCmpXchg(builder: LLVMBuilderRef, ptr: LLVMValueRef, cmp: LLVMValueRef, new: LLVMValueRef, success: LLVMAtomicOrdering, failure: LLVMAtomicOrdering, single_thread: LLVMBool) -> LLVMValueRef;
+    fn LLVMBuildAtomicRMW(builder: LLVMBuilderRef, op: c_uint, ptr: LLVMValueRef, val: LLVMValueRef, ordering: LLVMAtomicOrdering, single_thread: LLVMBool) -> LLVMValueRef;
+}
+
+const ATOMIC_RMW_FADD: c_uint = 11;
+// `amdgpu_kernel`, the calling convention enum of llvm-sys 38 does not list it
+const AMDGPU_KERNEL_CALL_CONV: c_uint = 91;
+
+fn add_param_attribute(func: LLVMValueRef, index: u32, attr: &str, context: LLVMContextRef) {
+    unsafe {
+        let kind = LLVMGetEnumAttributeKindForName(attr.as_ptr() as *const _, attr.len());
+        LLVMAddAttributeAtIndex(func, index + 1, LLVMCreateEnumAttribute(context, kind, 0));
+    }
+}
+
+fn add_function_attribute(func: LLVMValueRef, key: &str, value: &str, context: LLVMContextRef) {
+    unsafe {
+        let attr = LLVMCreateStringAttribute(context, key.as_ptr() as *const _, key.len() as u32, value.as_ptr() as *const _, value.len() as u32);
+        // the function itself sits at index ~0
+        LLVMAddAttributeAtIndex(func, !0, attr);
+    }
+}
 
 /*
 Current primitives approach :
+    i8 <: i16 <: i32
     i32 <: i64
     f32 <: f64
     i64 <: f64
     i32 <: f32
+    f16 <: f32
+    bf16 <: f32
+    mut T <: T
+    [T; n..] <: [T]
 */
-fn subtype_check(t: &TyName, s: &TyName, step: &mut u32) -> bool {
+pub(crate) fn subtype_check(t: &TyName, s: &TyName, step: &mut u32) -> bool {
     *step = *step + 1;
     println!("subtype check src: {:?} dest: {:?}", t, s);
     if *s == *t {
         return true;
     }
+    // a mutable binding can always be used where an immutable one is expected
+    if let (TyName::MutBind(src), TyName::MutBind(dest)) = (t, s) {
+        return subtype_check(&**src, &**dest, step);
+    }
+    if let TyName::MutBind(src) = t {
+        return subtype_check(&**src, s, step);
+    }
+    // extents are bound per function, so shapes only have to agree on element type, rank and layout
+    match (t, s) {
+        (TyName::Dense(src, src_shape, src_layout), TyName::Dense(dest, dest_shape, dest_layout)) => {
+            return src == dest && src_shape.len() == dest_shape.len() && src_layout == dest_layout;
+        }
+        (TyName::Dense(src, _, _), TyName::Array(dest)) => {
+            return src == dest;
+        }
+        _ => {}
+    }
     if let (TyName::NameBind(src), TyName::NameBind(dest)) = (t, s) {
         println!("name bind comparison src: {} dest: {}", src, dest);
+        if src[..].eq("i8") {
+            return subtype_check(&TyName::NameBind(String::from("i16")), s, step);
+        }
+        if src[..].eq("i16") {
+            return subtype_check(&TyName::NameBind(String::from("i32")), s, step);
+        }
         if src[..].eq("i32") {
             return subtype_check(&TyName::NameBind(String::from("i64")), s, step)
                 || subtype_check(&TyName::NameBind(String::from("f32")), s, step);
@@ -36,12 +101,21 @@ fn subtype_check(t: &TyName, s: &TyName, step: &mut u32) -> bool {
         if src[..].eq("i64") {
             return subtype_check(&TyName::NameBind(String::from("f64")), s, step);
         }
+        if src[..].eq("f16") || src[..].eq("bf16") {
+            return subtype_check(&TyName::NameBind(String::from("f32")), s, step);
+        }
     }
     false
 }
 
 fn gen_subtype_cast(src: &TyName, dest: &TyName, src_val: LLVMValueRef, context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
     if src == dest { return src_val; }
+    if let (TyName::MutBind(src), TyName::MutBind(dest)) = (src, dest) {
+        return gen_subtype_cast(&**src, &**dest, src_val, context, builder);
+    }
+    if let TyName::MutBind(src) = src {
+        return gen_subtype_cast(&**src, dest, src_val, context, builder);
+    }
     if let (TyName::NameBind(src_name), TyName::NameBind(dest_name)) = (src, dest) {
         match (&src_name[..], &dest_name[..]) {
             ("i32", "i64") => {
@@ -59,6 +133,25 @@ fn gen_subtype_cast(src: &TyName, dest: &TyName, src_val: LLVMValueRef, context:
             ("f32", "f64") => {
                 return unsafe { LLVMBuildFPExt(builder, src_val, LLVMDoubleTypeInContext(context), b"casttmp\0".as_ptr() as *mut _) };
             }
+            ("f16", "f32") => {
+                return unsafe { LLVMBuildFPExt(builder, src_val, LLVMFloatTypeInContext(context), b"casttmp\0".as_ptr() as *mut _) };
+            }
+            ("f16", "f64") => {
+                return unsafe { LLVMBuildFPExt(builder, src_val, LLVMDoubleTypeInContext(context), b"casttmp\0".as_ptr() as *mut _) };
+            }
+            ("bf16", "f32") => {
+                return build_bf16_widen(src_val, context, builder);
+            }
+            ("bf16", "f64") => {
+                return unsafe { LLVMBuildFPExt(builder, build_bf16_widen(src_val, context, builder), LLVMDoubleTypeInContext(context), b"casttmp\0".as_ptr() as *mut _) };
+            }
+            // narrow integers widen through i32
+            ("i8", _) | ("i16", _) if dest_name.starts_with('i') => {
+                return unsafe { LLVMBuildSExt(builder, src_val, map_type(dest, context, false), b"casttmp\0".as_ptr() as *mut _) };
+            }
+            ("i8", _) | ("i16", _) if dest_name.starts_with('f') => {
+                return unsafe { LLVMBuildSIToFP(builder, src_val, map_type(dest, context, false), b"casttmp\0".as_ptr() as *mut _) };
+            }
             _ => {}
         }
     }
@@ -66,8 +159,310 @@ fn gen_subtype_cast(src: &TyName, dest: &TyName, src_val: LLVMValueRef, context:
     // But we just slide it to llvm to raise error for now
 }
 
+// Integer constant of the given type, repeated in every lane for vector types
+fn const_lanes(ty: LLVMTypeRef, val: u64) -> LLVMValueRef {
+    unsafe {
+        match LLVMGetTypeKind(ty) {
+            LLVMTypeKind::LLVMVectorTypeKind => {
+                let lanes = LLVMGetVectorSize(ty);
+                let mut elems = vec![LLVMConstInt(LLVMGetElementType(ty), val, 0); lanes as usize];
+                LLVMConstVector(elems.as_mut_ptr(), lanes)
+            }
+            _ => LLVMConstInt(ty, val, 0)
+        }
+    }
+}
+
+// Same shape as `ty` with its scalar replaced, so the bf16 helpers work on scalars and vectors alike
+fn with_scalar(ty: LLVMTypeRef, scalar: LLVMTypeRef) -> LLVMTypeRef {
+    unsafe {
+        match LLVMGetTypeKind(ty) {
+            LLVMTypeKind::LLVMVectorTypeKind => LLVMVectorType(scalar, LLVMGetVectorSize(ty)),
+            _ => scalar
+        }
+    }
+}
+
+// bf16 is kept as its raw bits in an i16, they are the upper half of the equivalent f32
+fn build_bf16_widen(val: LLVMValueRef, context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    unsafe {
+        let ty = LLVMTypeOf(val);
+        let i32_type = with_scalar(ty, LLVMInt32TypeInContext(context));
+        let ext = LLVMBuildZExt(builder, val, i32_type, b"bf16tmp\0".as_ptr() as *mut _);
+        let bits = LLVMBuildShl(builder, ext, const_lanes(i32_type, 16), b"bf16tmp\0".as_ptr() as *mut _);
+        LLVMBuildBitCast(builder, bits, with_scalar(ty, LLVMFloatTypeInContext(context)), b"bf16tmp\0".as_ptr() as *mut _)
+    }
+}
+
+// Rounds an f32 to nearest even bf16, NaNs become the canonical quiet NaN
+fn build_bf16_narrow(val: LLVMValueRef, context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    unsafe {
+        let ty = LLVMTypeOf(val);
+        let i32_type = with_scalar(ty, LLVMInt32TypeInContext(context));
+        let i16_type = with_scalar(ty, LLVMInt16TypeInContext(context));
+        let name = b"bf16tmp\0".as_ptr() as *mut _;
+        let bits = LLVMBuildBitCast(builder, val, i32_type, name);
+        let lsb = LLVMBuildAnd(builder, LLVMBuildLShr(builder, bits, const_lanes(i32_type, 16), name), const_lanes(i32_type, 1), name);
+        let rounded = LLVMBuildAdd(builder, bits, LLVMBuildAdd(builder, lsb, const_lanes(i32_type, 0x7fff), name), name);
+        let top = LLVMBuildTrunc(builder, LLVMBuildLShr(builder, rounded, const_lanes(i32_type, 16), name), i16_type, name);
+        let nan = LLVMBuildFCmp(builder, LLVMRealPredicate::LLVMRealUNO, val, val, name);
+        LLVMBuildSelect(builder, nan, const_lanes(i16_type, 0x7fc0), top, name)
+    }
+}
+
+const SCALAR_TYPES: [&str; 8] = ["i8", "i16", "i32", "i64", "f16", "bf16", "f32", "f64"];
+
+fn is_float_name(name: &str) -> bool {
+    name.starts_with('f') || name.eq("bf16")
+}
+
+fn scalar_bits(name: &str) -> u32 {
+    name.trim_start_matches(|c| c == 'i' || c == 'f' || c == 'b').parse().unwrap_or(0)
+}
+
+// Short vectors are spelled `<scalar>x<lanes>`, e.g. f32x4 or i32x2
+pub(crate) fn vector_type(name: &str) -> Option<(String, u32)> {
+    let split = name.rfind('x')?;
+    let lanes = name[split + 1..].parse::<u32>().ok()?;
+    if SCALAR_TYPES.contains(&&name[..split]) && lanes >= 2 {
+        Some((name[..split].to_string(), lanes))
+    } else {
+        None
+    }
+}
+
+pub(crate) fn vector_type_of(ty: &TyName) -> Option<(String, u32)> {
+    if let TyName::NameBind(name) = ty { vector_type(name) } else { None }
+}
+
+// Explicit conversion between scalar primitives, as used by vector constructors
+fn gen_scalar_convert(src: &TyName, dest: &str, src_val: LLVMValueRef, context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    let src_name = match src {
+        TyName::NameBind(name) if SCALAR_TYPES.contains(&&name[..]) => name.clone(),
+        _ => panic!("Cannot convert {:?} to `{}`", src, dest)
+    };
+    if src_name.eq(dest) { return src_val; }
+    if src_name.eq("bf16") {
+        let wide = build_bf16_widen(src_val, context, builder);
+        return gen_scalar_convert(&TyName::NameBind(String::from("f32")), dest, wide, context, builder);
+    }
+    if dest.eq("bf16") {
+        let wide = gen_scalar_convert(src, "f32", src_val, context, builder);
+        return build_bf16_narrow(wide, context, builder);
+    }
+    let dest_type = map_type(&TyName::NameBind(dest.to_string()), context, false);
+    unsafe {
+        match (is_float_name(&src_name), is_float_name(dest)) {
+            (false, false) => LLVMBuildIntCast(builder, src_val, dest_type, b"convtmp\0".as_ptr() as *mut _),
+            (false, true) => LLVMBuildSIToFP(builder, src_val, dest_type, b"convtmp\0".as_ptr() as *mut _),
+            (true, false) => LLVMBuildFPToSI(builder, src_val, dest_type, b"convtmp\0".as_ptr() as *mut _),
+            (true, true) => LLVMBuildFPCast(builder, src_val, dest_type, b"convtmp\0".as_ptr() as *mut _),
+        }
+    }
+}
+
+fn build_splat(val: LLVMValueRef, lanes: u32, context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    unsafe {
+        let vec_type = LLVMVectorType(LLVMTypeOf(val), lanes);
+        let zero = LLVMConstInt(LLVMInt32TypeInContext(context), 0, 0);
+        let single = LLVMBuildInsertElement(builder, LLVMGetUndef(vec_type), val, zero, b"splattmp\0".as_ptr() as *mut _);
+        let mut mask = vec![zero; lanes as usize];
+        LLVMBuildShuffleVector(builder, single, LLVMGetUndef(vec_type), LLVMConstVector(mask.as_mut_ptr(), lanes), b"splattmp\0".as_ptr() as *mut _)
+    }
+}
+
+// `f32x4(a, b, c, d)` builds a vector lane by lane, `f32x4(a)` broadcasts a scalar
+fn build_vector_ctor(name: &str, params: Vec<(LLVMValueRef, TyName)>, context: LLVMContextRef, builder: LLVMBuilderRef) -> (LLVMValueRef, TyName) {
+    let (elem, lanes) = vector_type(name).unwrap();
+    if params.len() == 1 {
+        let scalar = gen_scalar_convert(&params[0].1, &elem, params[0].0, context, builder);
+        return (build_splat(scalar, lanes, context, builder), TyName::NameBind(name.to_string()));
+    }
+    if params.len() != lanes as usize {
+        panic!("`{}` expects 1 or {} components, {} given", name, lanes, params.len());
+    }
+    let mut vec = unsafe { LLVMGetUndef(map_type(&TyName::NameBind(name.to_string()), context, false)) };
+    for (i, param) in params.into_iter().enumerate() {
+        let scalar = gen_scalar_convert(&param.1, &elem, param.0, context, builder);
+        vec = unsafe { LLVMBuildInsertElement(builder, vec, scalar, LLVMConstInt(LLVMInt32TypeInContext(context), i as u64, 0), b"vectmp\0".as_ptr() as *mut _) };
+    }
+    (vec, TyName::NameBind(name.to_string()))
+}
+
+// Swizzles pick lanes by `xyzw`, a single lane yields the scalar element
+fn build_swizzle(vec: (LLVMValueRef, TyName), field: &str, context: LLVMContextRef, builder: LLVMBuilderRef) -> (LLVMValueRef, TyName) {
+    let (elem, lanes) = vector_type_of(&vec.1).unwrap_or_else(|| panic!("Type {:?} has no member `{}`", vec.1, field));
+    let mut indices: Vec<LLVMValueRef> = field.chars().map(|c| match "xyzw".find(c) {
+        Some(i) if (i as u32) < lanes => unsafe { LLVMConstInt(LLVMInt32TypeInContext(context), i as u64, 0) },
+        _ => panic!("Invalid component `{}` in swizzle `.{}` of `{}x{}`", c, field, elem, lanes)
+    }).collect();
+    unsafe {
+        match indices.len() {
+            0 => panic!("Empty member access on `{}x{}`", elem, lanes),
+            1 => (LLVMBuildExtractElement(builder, vec.0, indices[0], b"swizzletmp\0".as_ptr() as *mut _), TyName::NameBind(elem)),
+            n => (LLVMBuildShuffleVector(builder, vec.0, LLVMGetUndef(LLVMTypeOf(vec.0)), LLVMConstVector(indices.as_mut_ptr(), n as u32), b"swizzletmp\0".as_ptr() as *mut _),
+                  TyName::NameBind(format!("{}x{}", elem, n)))
+        }
+    }
+}
+
+fn is_vector_arith(ident: &str, params: &Vec<(LLVMValueRef, TyName)>) -> bool {
+    ["add", "subtract", "multiply", "divide"].contains(&ident) && params.len() == 2
+        && params.iter().any(|p| vector_type_of(&p.1).is_some())
+}
+
+// Element-wise arithmetic on vectors, a scalar operand is broadcast to every lane
+fn build_vector_arith(ident: &str, mut params: Vec<(LLVMValueRef, TyName)>, opts: &CodegenOptions, context: LLVMContextRef, builder: LLVMBuilderRef) -> (LLVMValueRef, TyName) {
+    let vec_ty = params.iter().find(|p| vector_type_of(&p.1).is_some()).unwrap().1.clone();
+    let (elem, lanes) = vector_type_of(&vec_ty).unwrap();
+    for param in params.iter_mut() {
+        if param.1 == vec_ty { continue; }
+        let mut step = 0;
+        if vector_type_of(&param.1).is_some() || !subtype_check(&param.1, &TyName::NameBind(elem.clone()), &mut step) {
+            panic!("Mismatched operands for `{}`: {:?} and {:?}", ident, param.1, vec_ty);
+        }
+        let scalar = gen_subtype_cast(&param.1, &TyName::NameBind(elem.clone()), param.0, context, builder);
+        *param = (build_splat(scalar, lanes, context, builder), vec_ty.clone());
+    }
+    let lhs = build_half_widen(params[0].0, &elem[..], opts, context, builder);
+    let rhs = build_half_widen(params[1].0, &elem[..], opts, context, builder);
+    let val = build_arith_op(ident, is_float_name(&elem), lhs, rhs, b"vectmp\0", builder);
+    (build_half_narrow(val, &elem[..], opts, context, builder), vec_ty)
+}
+
+fn build_arith_op(ident: &str, float: bool, lhs: LLVMValueRef, rhs: LLVMValueRef, name: &[u8], builder: LLVMBuilderRef) -> LLVMValueRef {
+    let name = name.as_ptr() as *mut _;
+    unsafe {
+        match (ident, float) {
+            ("add", true) => LLVMBuildFAdd(builder, lhs, rhs, name),
+            ("add", false) => LLVMBuildAdd(builder, lhs, rhs, name),
+            ("subtract", true) => LLVMBuildFSub(builder, lhs, rhs, name),
+            ("subtract", false) => LLVMBuildSub(builder, lhs, rhs, name),
+            ("multiply", true) => LLVMBuildFMul(builder, lhs, rhs, name),
+            ("multiply", false) => LLVMBuildMul(builder, lhs, rhs, name),
+            ("divide", true) => LLVMBuildFDiv(builder, lhs, rhs, name),
+            _ => LLVMBuildSDiv(builder, lhs, rhs, name),
+        }
+    }
+}
+
+// Half precision is computed in f32 unless the target has f16 instructions, bf16 always is.
+// Both work on scalars and vectors alike, other element types are left as they are
+fn build_half_widen(val: LLVMValueRef, elem: &str, opts: &CodegenOptions, context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    match elem {
+        "bf16" => build_bf16_widen(val, context, builder),
+        "f16" if !opts.native_half => unsafe {
+            LLVMBuildFPExt(builder, val, with_scalar(LLVMTypeOf(val), LLVMFloatTypeInContext(context)), b"halftmp\0".as_ptr() as *mut _)
+        }
+        _ => val
+    }
+}
+
+fn build_half_narrow(val: LLVMValueRef, elem: &str, opts: &CodegenOptions, context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    match elem {
+        "bf16" => build_bf16_narrow(val, context, builder),
+        "f16" if !opts.native_half => unsafe {
+            LLVMBuildFPTrunc(builder, val, with_scalar(LLVMTypeOf(val), LLVMHalfTypeInContext(context)), b"halftmp\0".as_ptr() as *mut _)
+        }
+        _ => val
+    }
+}
+
+// arith.ru only declares the f16 and bf16 arithmetic and `bfloat`, calls resolved to them are lowered here
+fn build_half_builtin(ident: &str, params: &[(LLVMValueRef, TyName)], ret: &TyName, opts: &CodegenOptions, context: LLVMContextRef, builder: LLVMBuilderRef) -> Option<LLVMValueRef> {
+    let elem = match ret {
+        TyName::NameBind(name) if name.eq("f16") || name.eq("bf16") => &name[..],
+        _ => return None
+    };
+    if ident.eq("bfloat") && params.len() == 1 {
+        return Some(gen_scalar_convert(&params[0].1, elem, params[0].0, context, builder));
+    }
+    if !["add", "subtract", "multiply", "divide"].contains(&ident) || params.len() != 2 || params.iter().any(|p| p.1 != *ret) {
+        return None;
+    }
+    let lhs = build_half_widen(params[0].0, elem, opts, context, builder);
+    let rhs = build_half_widen(params[1].0, elem, opts, context, builder);
+    let val = build_arith_op(ident, true, lhs, rhs, b"halftmp\0", builder);
+    Some(build_half_narrow(val, elem, opts, context, builder))
+}
+
+const COMPARISONS: [&str; 6] = ["eq", "ne", "lt", "gt", "lt_eq", "gt_eq"];
 
-fn map_type(ty: &TyName, context: LLVMContextRef, set_mut: bool, device_side: bool) -> *mut LLVMType {
+fn is_primitive(ty: &TyName) -> bool {
+    match ty {
+        TyName::NameBind(name) => name.eq("bool") || SCALAR_TYPES.contains(&&name[..]),
+        TyName::MutBind(inner) => is_primitive(&**inner),
+        _ => false
+    }
+}
+
+pub(crate) fn is_builtin_compare(ident: &str, args: &Vec<TyName>) -> bool {
+    COMPARISONS.contains(&ident) && args.len() == 2 && args.iter().all(is_primitive)
+}
+
+// Both operands are promoted to the narrowest type they are subtypes of
+pub(crate) fn compare_type(lhs: &TyName, rhs: &TyName) -> Option<TyName> {
+    let candidates = [lhs.clone(), rhs.clone(), TyName::NameBind(String::from("i64")), TyName::NameBind(String::from("f32")), TyName::NameBind(String::from("f64"))];
+    candidates.iter().find(|ty| subtype_check(lhs, ty, &mut 0) && subtype_check(rhs, ty, &mut 0)).map(|ty| match ty {
+        TyName::MutBind(inner) => (**inner).clone(),
+        _ => ty.clone()
+    })
+}
+
+// Integers compare signed, bool compares unsigned, floats use ordered predicates except `!=` which holds for NaN
+fn build_compare(ident: &str, params: Vec<(LLVMValueRef, TyName)>, context: LLVMContextRef, builder: LLVMBuilderRef) -> (LLVMValueRef, TyName) {
+    let ty = compare_type(&params[0].1, &params[1].1)
+        .unwrap_or_else(|| panic!("Cannot compare {:?} with {:?}", params[0].1, params[1].1));
+    let name = match &ty { TyName::NameBind(name) => name.clone(), _ => unreachable!() };
+    let mut lhs = gen_subtype_cast(&params[0].1, &ty, params[0].0, context, builder);
+    let mut rhs = gen_subtype_cast(&params[1].1, &ty, params[1].0, context, builder);
+    if name.eq("bf16") {
+        lhs = build_bf16_widen(lhs, context, builder);
+        rhs = build_bf16_widen(rhs, context, builder);
+    }
+    let cmp = b"cmptmp\0".as_ptr() as *const _;
+    let val = unsafe {
+        if is_float_name(&name) {
+            let predicate = match ident {
+                "eq" => LLVMRealPredicate::LLVMRealOEQ,
+                "ne" => LLVMRealPredicate::LLVMRealUNE,
+                "lt" => LLVMRealPredicate::LLVMRealOLT,
+                "gt" => LLVMRealPredicate::LLVMRealOGT,
+                "lt_eq" => LLVMRealPredicate::LLVMRealOLE,
+                _ => LLVMRealPredicate::LLVMRealOGE,
+            };
+            LLVMBuildFCmp(builder, predicate, lhs, rhs, cmp)
+        } else {
+            let signed = !name.eq("bool");
+            let predicate = match (ident, signed) {
+                ("eq", _) => LLVMIntPredicate::LLVMIntEQ,
+                ("ne", _) => LLVMIntPredicate::LLVMIntNE,
+                ("lt", true) => LLVMIntPredicate::LLVMIntSLT,
+                ("lt", false) => LLVMIntPredicate::LLVMIntULT,
+                ("gt", true) => LLVMIntPredicate::LLVMIntSGT,
+                ("gt", false) => LLVMIntPredicate::LLVMIntUGT,
+                ("lt_eq", true) => LLVMIntPredicate::LLVMIntSLE,
+                ("lt_eq", false) => LLVMIntPredicate::LLVMIntULE,
+                (_, true) => LLVMIntPredicate::LLVMIntSGE,
+                (_, false) => LLVMIntPredicate::LLVMIntUGE,
+            };
+            LLVMBuildICmp(builder, predicate, lhs, rhs, cmp)
+        }
+    };
+    (val, TyName::NameBind(String::from("bool")))
+}
+
+// Vector accesses are aligned to their full width so they become single 64/128-bit memory operations
+fn set_vector_alignment(inst: LLVMValueRef, ty: &TyName) {
+    if let Some((elem, lanes)) = vector_type_of(ty) {
+        let bytes = scalar_bits(&elem) * lanes / 8;
+        if bytes.is_power_of_two() {
+            unsafe { LLVMSetAlignment(inst, bytes) };
+        }
+    }
+}
+
+fn map_type(ty: &TyName, context: LLVMContextRef, device_side: bool) -> *mut LLVMType {
     match ty {
         TyName::NameBind(name) => {
             unsafe {
@@ -77,18 +472,38 @@ fn map_type(ty: &TyName, context: LLVMContextRef, set_mut: bool, device_side: bo
                     "i16" => LLVMInt16TypeInContext(context),
                     "i32" => LLVMInt32TypeInContext(context),
                     "i64" => LLVMInt64TypeInContext(context),
+                    "f16" => LLVMHalfTypeInContext(context),
+                    "bf16" => LLVMInt16TypeInContext(context),
                     "f32" => LLVMFloatTypeInContext(context),
                     "f64" => LLVMDoubleTypeInContext(context),
-                    _ => LLVMVoidTypeInContext(context)
+                    _ => match vector_type(name) {
+                        Some((elem, lanes)) => LLVMVectorType(map_type(&TyName::NameBind(elem), context, device_side), lanes),
+                        None => LLVMVoidTypeInContext(context)
+                    }
                 }
             }
         }
-        TyName::Array(ty) => {
-// set to constant address space if necessary
-            unsafe { LLVMPointerType(map_type(&**ty, context, false, device_side), if device_side { if set_mut { 1 } else { 1 } } else { 0 }) }
+        TyName::Array(ty) | TyName::Dense(ty, _, _) => {
+// mutability is checked on the type level, it does not change the address space
+            unsafe { LLVMPointerType(map_type(&**ty, context, device_side), if device_side { AddressSpace::Global as u32 } else { AddressSpace::Generic as u32 }) }
         }
         TyName::MutBind(ty) => {
-            map_type(&**ty, context, true, device_side)
+            map_type(&**ty, context, device_side)
+        }
+        TyName::Record(name, fields) => unsafe {
+            let mut field_types: Vec<_> = fields.iter().map(|f| map_type(&f.1, context, device_side)).collect();
+            if name.is_empty() {
+                return LLVMStructTypeInContext(context, field_types.as_mut_ptr(), field_types.len() as u32, 0);
+            }
+            // named records are uniqued by the context, their body is set on first use
+            let name = CString::new(name.clone()).unwrap();
+            let existing = LLVMGetTypeByName2(context, name.as_ptr());
+            if !existing.is_null() {
+                return existing;
+            }
+            let record = LLVMStructCreateNamed(context, name.as_ptr());
+            LLVMStructSetBody(record, field_types.as_mut_ptr(), field_types.len() as u32, 0);
+            record
         }
         _ => unsafe { LLVMVoidTypeInContext(context) }
     }
@@ -97,17 +512,205 @@ fn map_type(ty: &TyName, context: LLVMContextRef, set_mut: bool, device_side: bo
 use llvm_sys::prelude::*;
 use llvm_sys::LLVMTypeKind;
 
+pub(crate) fn is_mutable(ty: &TyName) -> bool {
+    if let TyName::MutBind(_) = ty { true } else { false }
+}
+
+// Element type of an array, arrays nested in a `mut` array stay mutable
+pub(crate) fn element_type(ty: &TyName) -> Option<TyName> {
+    match ty {
+        TyName::Array(elem) | TyName::Dense(elem, _, _) => Some((**elem).clone()),
+        TyName::MutBind(inner) => element_type(&**inner).map(|elem| match elem {
+            TyName::Array(_) | TyName::Dense(..) => TyName::MutBind(Box::new(elem)),
+            _ => elem
+        }),
+        _ => None
+    }
+}
+
+pub(crate) fn shape_of(ty: &TyName) -> Option<(Vec<String>, Layout)> {
+    match ty {
+        TyName::Dense(_, extents, layout) => Some((extents.clone(), *layout)),
+        TyName::MutBind(inner) => shape_of(&**inner),
+        _ => None
+    }
+}
+
+// Plain kernel arrays carry their length as a hidden parameter named `<array>.len`
+pub(crate) fn shape_kernel_arrays(params: Vec<(String, TyName)>) -> Vec<(String, TyName)> {
+    params.into_iter().map(|(name, ty)| {
+        let length = vec![format!("{}.len", name)];
+        let shaped = match ty {
+            TyName::Array(elem) => TyName::Dense(elem, length, Layout::RowMajor),
+            TyName::MutBind(inner) => TyName::MutBind(Box::new(match *inner {
+                TyName::Array(elem) => TyName::Dense(elem, length, Layout::RowMajor),
+                other => other
+            })),
+            other => other
+        };
+        (name, shaped)
+    }).collect()
+}
+
+// Extents of shaped array parameters that are not parameters themselves,
+// they are appended to the signature as hidden i64 parameters in order of appearance
+pub(crate) fn hidden_params(params: &Vec<(String, TyName)>) -> Vec<(String, TyName)> {
+    let mut hidden: Vec<(String, TyName)> = vec![];
+    for (_, ty) in params.iter() {
+        for extent in shape_of(ty).map(|s| s.0).unwrap_or(vec![]) {
+            if extent.parse::<u64>().is_err() && !params.iter().chain(hidden.iter()).any(|p| p.0.eq(&extent)) {
+                hidden.push((extent, TyName::NameBind(String::from("i64"))));
+            }
+        }
+    }
+    hidden
+}
+
+// A parfun with more than three indices folds the ones after the second onto z, the extents of the folded
+// indices are appended as hidden i64 parameters named `<index>.extent`, after the hidden array extents
+pub(crate) fn folded_params(para_in: &Vec<String>) -> Vec<(String, TyName)> {
+    if para_in.len() <= 3 {
+        return vec![];
+    }
+    para_in.iter().enumerate().skip(2).map(|(k, par)| {
+        let name = if par.eq("_") { format!("dim{}", k) } else { par.clone() };
+        (format!("{}.extent", name), TyName::NameBind(String::from("i64")))
+    }).collect()
+}
+
+fn build_extent(extent: &str, val_context: &Vec<HashMap<String, (LLVMValueRef, TyName)>>, context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    unsafe {
+        let i64_type = LLVMInt64TypeInContext(context);
+        match extent.parse::<u64>() {
+            Ok(v) => LLVMConstInt(i64_type, v, 0),
+            Err(_) => {
+                let val = val_context.iter().find(|map| map.contains_key(extent))
+                    .unwrap_or_else(|| panic!("Extent `{}` is not in scope", extent))[extent].0;
+                LLVMBuildIntCast(builder, val, i64_type, b"exttmp\0".as_ptr() as *mut _)
+            }
+        }
+    }
+}
+
+// Row-major offsets are ((i0 * e1 + i1) * e2 + i2).., column-major ones run the same recurrence from the last index
+fn build_dense_offset(indices: &[LLVMValueRef], extents: &[LLVMValueRef], layout: Layout, context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    let mut order: Vec<usize> = (0..indices.len()).collect();
+    if layout == Layout::ColMajor {
+        order.reverse();
+    }
+    unsafe {
+        let i64_type = LLVMInt64TypeInContext(context);
+        let mut offset = LLVMBuildIntCast(builder, indices[order[0]], i64_type, b"idxtmp\0".as_ptr() as *mut _);
+        for &k in order.iter().skip(1) {
+            let index = LLVMBuildIntCast(builder, indices[k], i64_type, b"idxtmp\0".as_ptr() as *mut _);
+            let scaled = LLVMBuildMul(builder, offset, extents[k], b"offsettmp\0".as_ptr() as *mut _);
+            offset = LLVMBuildAdd(builder, scaled, index, b"offsettmp\0".as_ptr() as *mut _);
+        }
+        offset
+    }
+}
+
+// Checks every index against its extent, out-of-range accesses print their location and trap
+fn build_bounds_check(indices: &[LLVMValueRef], extents: &[LLVMValueRef], intrinsic: &str, nv: &NVIntrinsics, loc: &SrcLoc, context: LLVMContextRef, builder: LLVMBuilderRef) {
+    unsafe {
+        let func = LLVMGetBasicBlockParent(LLVMGetInsertBlock(builder));
+        let mut in_range = LLVMConstInt(LLVMInt1TypeInContext(context), 1, 0);
+        for (index, extent) in indices.iter().zip(extents.iter()) {
+            let index = LLVMBuildIntCast(builder, *index, LLVMInt64TypeInContext(context), b"idxtmp\0".as_ptr() as *mut _);
+            // negative indices wrap around in the unsigned comparison
+            let check = LLVMBuildICmp(builder, LLVMIntPredicate::LLVMIntULT, index, *extent, b"boundtmp\0".as_ptr() as *mut _);
+            in_range = LLVMBuildAnd(builder, in_range, check, b"boundtmp\0".as_ptr() as *mut _);
+        }
+        let trap_block = LLVMAppendBasicBlockInContext(context, func, b"outofbounds\0".as_ptr() as *const _);
+        let next_block = LLVMAppendBasicBlockInContext(context, func, b"inbounds\0".as_ptr() as *const _);
+        LLVMBuildCondBr(builder, in_range, next_block, trap_block);
+        LLVMPositionBuilderAtEnd(builder, trap_block);
+        let message = CString::new(format!("{}: index out of bounds in `{}`\n", loc, intrinsic).replace("%", "%%")).unwrap();
+        // amdgcn has no vprintf, the access only traps there
+        if !nv.vprintf.is_null() {
+            let mut args = [LLVMBuildGlobalStringPtr(builder, message.as_ptr(), b"oobmsg\0".as_ptr() as *const _),
+                LLVMConstNull(LLVMPointerType(LLVMInt8TypeInContext(context), 0))];
+            LLVMBuildCall(builder, nv.vprintf, args.as_mut_ptr(), 2, b"printtmp\0".as_ptr() as *const _);
+        }
+        LLVMBuildCall(builder, nv.trap, [].as_mut_ptr(), 0, b"\0".as_ptr() as *const _);
+        LLVMBuildUnreachable(builder);
+        LLVMPositionBuilderAtEnd(builder, next_block);
+    }
+}
+
+fn build_element_ptr(array: &(LLVMValueRef, TyName), indices: &[(LLVMValueRef, TyName)], intrinsic: &str, val_context: &Vec<HashMap<String, (LLVMValueRef, TyName)>>, context: LLVMContextRef, builder: LLVMBuilderRef, nv: &NVIntrinsics, opts: &CodegenOptions, loc: &SrcLoc) -> LLVMValueRef {
+    let mut offset = match shape_of(&array.1) {
+        Some((extents, layout)) => {
+            if indices.len() != extents.len() {
+                panic!("{}: `{}` on a rank {} array expects {} indices, {} given", loc, intrinsic, extents.len(), extents.len(), indices.len());
+            }
+            let extents: Vec<_> = extents.iter().map(|e| build_extent(e, val_context, context, builder)).collect();
+            let indices: Vec<_> = indices.iter().map(|i| i.0).collect();
+            if opts.bounds_check && !opts.proven.contains(loc) {
+                build_bounds_check(&indices, &extents, intrinsic, nv, loc, context, builder);
+            }
+            build_dense_offset(&indices, &extents, layout, context, builder)
+        }
+        None => {
+            // arrays without a known shape, e.g. rows of a jagged array, cannot be checked
+            if indices.len() != 1 {
+                panic!("{}: `{}` on an unshaped array expects 1 index, {} given", loc, intrinsic, indices.len());
+            }
+            indices[0].0
+        }
+    };
+    unsafe { LLVMBuildGEP(builder, array.0, &mut offset as *mut _, 1, b"loadtmp\0".as_ptr() as *mut _) }
+}
+
+// Hidden extents of the callee are taken from the shapes of the arrays passed in their place
+fn build_hidden_args(func: LLVMValueRef, params: &Vec<TyName>, args: &Vec<TyName>, val_context: &Vec<HashMap<String, (LLVMValueRef, TyName)>>, context: LLVMContextRef, builder: LLVMBuilderRef) -> Vec<LLVMValueRef> {
+    let count = unsafe { LLVMCountParams(func) } as usize;
+    (params.len()..count).map(|i| {
+        let name = Context::get_value_name(unsafe { LLVMGetParam(func, i as u32) });
+        params.iter().zip(args.iter()).filter_map(|(param, arg)| {
+            let param_shape = shape_of(param)?.0;
+            let arg_shape = shape_of(arg)?.0;
+            param_shape.iter().position(|e| e.eq(&name)).map(|k| arg_shape[k].clone())
+        }).next().map(|extent| build_extent(&extent, val_context, context, builder))
+            .unwrap_or_else(|| panic!("Cannot pass hidden extent `{}`, the array argument has no known shape", name))
+    }).collect()
+}
+
+// Device functions take arrays as generic pointers, so that the global arrays of kernels and their shared
+// arrays can both be passed. The address space is inferred back from the cast once the callee is inlined
+fn build_generic_args(func: LLVMValueRef, args: &mut Vec<LLVMValueRef>, builder: LLVMBuilderRef) {
+    unsafe {
+        for (k, arg) in args.iter_mut().enumerate().take(LLVMCountParams(func) as usize) {
+            let (from, to) = (LLVMTypeOf(*arg), LLVMTypeOf(LLVMGetParam(func, k as u32)));
+            if let (LLVMTypeKind::LLVMPointerTypeKind, LLVMTypeKind::LLVMPointerTypeKind) = (LLVMGetTypeKind(from), LLVMGetTypeKind(to)) {
+                if LLVMGetPointerAddressSpace(to) == AddressSpace::Generic as u32 && LLVMGetPointerAddressSpace(from) != AddressSpace::Generic as u32 {
+                    *arg = LLVMBuildAddrSpaceCast(builder, *arg, to, b"generictmp\0".as_ptr() as *const _);
+                }
+            }
+        }
+    }
+}
+
 fn reverse_type(ty: LLVMTypeRef, context: LLVMContextRef) -> TyName {
     match unsafe { LLVMGetTypeKind(ty) } {
         LLVMTypeKind::LLVMPointerTypeKind => {
             return TyName::Array(Box::new(reverse_type(unsafe { LLVMGetElementType(ty) }, context)));
         }
+        LLVMTypeKind::LLVMHalfTypeKind => {
+            return TyName::NameBind(String::from("f16"));
+        }
         LLVMTypeKind::LLVMFloatTypeKind => {
             return TyName::NameBind(String::from("f32"));
         }
         LLVMTypeKind::LLVMDoubleTypeKind => {
             return TyName::NameBind(String::from("f64"));
         }
+        LLVMTypeKind::LLVMVectorTypeKind => {
+            if let TyName::NameBind(elem) = reverse_type(unsafe { LLVMGetElementType(ty) }, context) {
+                return TyName::NameBind(format!("{}x{}", elem, unsafe { LLVMGetVectorSize(ty) }));
+            }
+            TyName::Unit
+        }
         LLVMTypeKind::LLVMIntegerTypeKind => {
             match unsafe { LLVMGetIntTypeWidth(ty) } {
                 1 => TyName::NameBind(String::from("bool")),
@@ -122,137 +725,816 @@ fn reverse_type(ty: LLVMTypeRef, context: LLVMContextRef) -> TyName {
     }
 }
 
-fn build_recurse_expr(expr: BaseExpr, module_decl: &HashMap<String, Vec<(LLVMValueRef, TyName)>>, expected_ret: TyName, context: LLVMContextRef, module: LLVMModuleRef, builder: LLVMBuilderRef, val_context: &mut Vec<HashMap<String, (LLVMValueRef, TyName)>>) -> (LLVMValueRef, TyName) {
+// Picks the overload whose parameters are reached from the arguments in the fewest subtyping steps
+pub(crate) fn select_overload(decls: &Vec<(LLVMValueRef, TyName)>, args: &Vec<TyName>) -> Option<(LLVMValueRef, Vec<TyName>, TyName)> {
+    let mut selected: Vec<(Vec<u32>, LLVMValueRef, Vec<TyName>, TyName)> = decls.iter().map(|(func_ref, ty)| {
+        if let TyName::Arrow(box_params, ret) = ty {
+            if let TyName::Tuple(params) = (**box_params).clone() {
+                if params.len() == args.len() {
+                    let checked: (Vec<bool>, Vec<u32>) = params.iter().zip(args.iter())
+                        .map(|(t1, t2)| {
+                            let mut steps = 0;
+                            (subtype_check(t2, t1, &mut steps), steps)
+                        }).unzip();
+                    if checked.0.iter().all(|x| *x) {
+                        return Ok((checked.1, *func_ref, params, *ret.clone()));
+                    }
+                }
+            }
+        }
+        Err(())
+    }).filter_map(|v| v.ok()).collect();
+    selected.sort_by(|v1, v2| {
+        v1.0.cmp(&v2.0)
+    });
+    selected.into_iter().next().map(|v| (v.1, v.2, v.3))
+}
+
+fn build_recurse_expr(expr: BaseExpr, module_decl: &HashMap<String, Vec<(LLVMValueRef, TyName)>>, expected_ret: TyName, context: LLVMContextRef, module: LLVMModuleRef, builder: LLVMBuilderRef, val_context: &mut Vec<HashMap<String, (LLVMValueRef, TyName)>>, nv: &NVIntrinsics, opts: &CodegenOptions, generics: &Generics) -> (LLVMValueRef, TyName) {
     match expr {
         BaseExpr::ConstantFloat(v) => unsafe { (LLVMConstReal(LLVMDoubleTypeInContext(context), v), TyName::NameBind(String::from("f64"))) },
         BaseExpr::ConstantInt(v) => unsafe { (LLVMConstInt(LLVMInt64TypeInContext(context), v as u64, 1), TyName::NameBind(String::from("i64"))) },
         BaseExpr::Return(ret) => {
-            let val = build_recurse_expr(ret.0, module_decl, expected_ret.clone(), context, module, builder, val_context);
+            let val = build_recurse_expr(ret.0, module_decl, expected_ret.clone(), context, module, builder, val_context, nv, opts, generics);
             let mut step = 0;
             assert!(subtype_check(dbg!(&val.1), dbg!(&expected_ret), &mut step));
             unsafe { (LLVMBuildRet(builder, gen_subtype_cast(&val.1, &expected_ret, val.0, context, builder)), val.1) }
         }
         BaseExpr::RetNull => {
-            unsafe { (LLVMBuildRetVoid(builder), TyName::Unit) }
+            match val_context.iter().find_map(|map| map.get("@continue")) {
+                Some((latch, _)) => unsafe { (LLVMBuildBr(builder, LLVMValueAsBasicBlock(*latch)), TyName::Unit) },
+                None => unsafe { (LLVMBuildRetVoid(builder), TyName::Unit) }
+            }
         }
         BaseExpr::LetDecl(id, _mutate, value) => {
-            let value = build_recurse_expr(value.0, module_decl, expected_ret, context, module, builder, val_context);
+            let value = build_recurse_expr(value.0, module_decl, expected_ret, context, module, builder, val_context, nv, opts, generics);
             let state: &mut _ = val_context.last_mut().unwrap();
             state.insert(id, value.clone());
             value
         }
+        BaseExpr::SharedDecl(id, ty) => {
+            let value = build_shared_decl(&id[..], &ty, context, module, builder);
+            val_context.last_mut().unwrap().insert(id, value.clone());
+            value
+        }
         BaseExpr::Ident(id) => {
-            val_context.iter_mut().find(|map|
-                map.contains_key(&id[..])).expect(format!("Could not find variable `{}` in current context !", id).as_str()).get_mut(&id[..]).unwrap().clone()
+            match val_context.iter().find_map(|map| map.get(&id[..])) {
+                Some(value) => value.clone(),
+                None => module_const(module_decl, &id[..]).expect(format!("Could not find variable `{}` in current context !", id).as_str())
+            }
         }
-        BaseExpr::FuncCall(ident, params) => {
+        BaseExpr::IfExpr(cond, body, next) => {
+            unsafe {
+                let func = LLVMGetBasicBlockParent(LLVMGetInsertBlock(builder));
+                let merge = LLVMAppendBasicBlockInContext(context, func, b"endif\0".as_ptr() as *const _);
+                build_if_chain(Some(*cond), body, next, merge, module_decl, expected_ret, context, module, builder, val_context, nv, opts, generics);
+                LLVMMoveBasicBlockAfter(merge, LLVMGetLastBasicBlock(func));
+                LLVMPositionBuilderAtEnd(builder, merge);
+                // every branch returned, nothing can reach the code after the `if`
+                if LLVMGetFirstUse(LLVMBasicBlockAsValue(merge)).is_null() {
+                    LLVMBuildUnreachable(builder);
+                }
+            }
+            (null_mut(), TyName::Unit)
+        }
+        BaseExpr::Member(obj, field) => {
+            let obj = build_recurse_expr(obj.0, module_decl, expected_ret, context, module, builder, val_context, nv, opts, generics);
+            match obj.1.clone() {
+                TyName::Record(name, fields) => {
+                    let index = fields.iter().position(|f| f.0.eq(&field)).unwrap_or_else(|| panic!("Record `{}` has no field `{}`", name, field));
+                    let cstring = CString::new(field).unwrap();
+                    unsafe { (LLVMBuildExtractValue(builder, obj.0, index as u32, cstring.as_ptr()), fields[index].1.clone()) }
+                }
+                _ => build_swizzle(obj, &field[..], context, builder)
+            }
+        }
+        BaseExpr::FuncCall(ident, mut params, loc) => {
+            let (count, options) = if ident.starts_with("@") {
+                split_options(&ident[..], &params).unwrap_or_else(|e| panic!("{}: {}", loc, e))
+            } else {
+                (params.len(), vec![])
+            };
+            params.truncate(count);
+            let literals = params.clone();
             let mut resolved: Vec<_> = params.into_iter()
-                .map(|v| build_recurse_expr(v.0, module_decl, expected_ret.clone(), context, module, builder, val_context)).collect();
+                .map(|v| build_recurse_expr(v.0, module_decl, expected_ret.clone(), context, module, builder, val_context, nv, opts, generics)).collect();
             if ident.starts_with("@") {
-                build_intrinsics(ident, resolved, context, module, builder)
+                let mut types: Vec<_> = resolved.iter().map(|v| v.1.clone()).collect();
+                adopt_literals(&ident[..], &literals, &mut types);
+                for (value, ty) in resolved.iter_mut().zip(types.into_iter()) {
+                    if value.1 != ty {
+                        *value = (unsafe { LLVMConstIntCast(value.0, map_type(&ty, context, false), 1) }, ty);
+                    }
+                }
+                check_intrinsic(&ident[..], &resolved.iter().map(|v| v.1.clone()).collect::<Vec<_>>()).unwrap_or_else(|e| panic!("{}: {}", loc, e));
+                build_intrinsics(ident, resolved, options, context, module, builder, val_context, nv, opts, &loc)
+            } else if vector_type(&ident[..]).is_some() {
+                build_vector_ctor(&ident[..], resolved, context, builder)
+            } else if is_vector_arith(&ident[..], &resolved) {
+                build_vector_arith(&ident[..], resolved, opts, context, builder)
+            } else if is_builtin_compare(&ident[..], &resolved.iter().map(|v| v.1.clone()).collect()) {
+                build_compare(&ident[..], resolved, context, builder)
             } else {
-                let empty = vec![];
-                let func_decls = module_decl.get(&ident[..]).unwrap_or(&empty);
-                //let cstring = CString::new(ident);
-                let mut target_ref: LLVMValueRef = null_mut();
-                let mut ret_val = TyName::Unit;
                 println!("trying to fecth {}", ident);
-                let mut selected: Vec<(Vec<u32>, LLVMValueRef, Vec<TyName>, TyName)> = func_decls.iter().map(|(func_ref, ty)| {
-                    println!("decl {:?}", ty);
-                    if let TyName::Arrow(box_params, ret) = ty {
-                        if let TyName::Tuple(params) = (**box_params).clone() {
-                            if params.len() == resolved.len() {
-                                let checked: (Vec<bool>, Vec<u32>) = params.iter().zip(resolved.iter().map(|v| &v.1))
-                                    .map(|(t1, t2)| {
-                                        let mut steps = 0;
-                                        (dbg!(subtype_check(t2, t1, &mut steps)), steps)
-                                    }).unzip();
-                                if checked.0.iter().all(|x| *x) {
-                                    return Ok((checked.1, *func_ref, params, *ret.clone()));
-                                }
-                            }
-                        }
+                let arg_types: Vec<_> = resolved.iter().map(|v| v.1.clone()).collect();
+                let func = match module_decl.get(&ident[..]).and_then(|decls| select_overload(decls, &arg_types)) {
+                    Some(func) => func,
+                    None => {
+                        // generic functions are only considered when no concrete overload applies
+                        let (type_args, params, ret) = generics.infer(&ident[..], &arg_types).unwrap_or_else(|e| panic!("{}: {}", loc, e));
+                        (generics.instantiate(&ident[..], &type_args, None, context, module, builder).0, params, ret)
                     }
-                    Err(())
-                }).filter_map(|v| v.ok()).collect();
-                selected.sort_by(|v1, v2| {
-                    v1.0.cmp(&v2.0)
-                });
-                let func = selected.into_iter().take(1).find(|v| true).unwrap_or_else(|| panic!("Identifier not in scope: `{}`", ident));
-                target_ref = func.1;
-                for i in 0..func.2.len() {
-                    resolved[i].0 = gen_subtype_cast(&resolved[i].1.clone(), &func.2[i].clone(), resolved[i].0, context, builder);
-                    resolved[i].1 = func.2[i].clone();
+                };
+                let target_ref = func.0;
+                for i in 0..func.1.len() {
+                    resolved[i].0 = gen_subtype_cast(&resolved[i].1.clone(), &func.1[i].clone(), resolved[i].0, context, builder);
+                    resolved[i].1 = func.1[i].clone();
                 }
-                ret_val = func.3;
+                let ret_val = func.2;
                 assert!(!target_ref.is_null());
+                if let Some(val) = build_half_builtin(&ident[..], &resolved, &ret_val, opts, context, builder) {
+                    return (val, ret_val);
+                }
+                let mut args: Vec<_> = resolved.into_iter().map(|v| v.0).collect();
+                args.extend(build_hidden_args(target_ref, &func.1, &arg_types, val_context, context, builder));
+                build_generic_args(target_ref, &mut args, builder);
                 unsafe {
-                    let param_len = resolved.len();
+                    let param_len = args.len();
+                    // calls returning nothing cannot be named
+                    let name: &[u8] = if ret_val == TyName::Unit { b"\0" } else { b"calltmp\0" };
                     (LLVMBuildCall(builder, target_ref,
-                                   resolved.into_iter().map(|v| v.0).collect::<Vec<_>>().deref_mut().as_mut_ptr(), param_len as u32, b"calltmp\0".as_ptr() as *mut _), ret_val)
+                                   args.deref_mut().as_mut_ptr(), param_len as u32, name.as_ptr() as *mut _), ret_val)
                 }
             }
         }
+        BaseExpr::Reduce { index, range, value, op, target, loc } => unsafe {
+            let range = build_recurse_expr(range.0, module_decl, expected_ret.clone(), context, module, builder, val_context, nv, opts, generics);
+            let range_type = range.1.clone();
+            let target: Vec<_> = target.into_iter()
+                .map(|v| build_recurse_expr(v.0, module_decl, expected_ret.clone(), context, module, builder, val_context, nv, opts, generics)).collect();
+            // every thread of the grid accumulates its share of the elements first
+            let func_name = Context::get_value_name(LLVMGetBasicBlockParent(LLVMGetInsertBlock(builder)));
+            let (thread, threads, block, blocks) = build_linear_ids(nv, context, builder);
+            let offset = LLVMBuildNUWMul(builder, block, threads, b"offsettmp\0".as_ptr() as *const _);
+            let start = LLVMBuildNUWAdd(builder, offset, thread, b"starttmp\0".as_ptr() as *const _);
+            let stride = LLVMBuildNUWMul(builder, threads, blocks, b"stridetmp\0".as_ptr() as *const _);
+            let entry = LLVMGetInsertBlock(builder);
+            let (element, latch, exit) = build_grid_stride(start, stride, range, &func_name[..], &index[..], context, builder);
+            let mut scope = HashMap::new();
+            scope.insert(index, (element, TyName::NameBind(String::from("i64"))));
+            val_context.push(scope);
+            let value = build_recurse_expr(value.0, module_decl, expected_ret, context, module, builder, val_context, nv, opts, generics);
+            val_context.pop();
+            let target_types: Vec<_> = target.iter().map(|v| v.1.clone()).collect();
+            let ty = check_reduce(&op[..], &range_type, &value.1, &target_types).unwrap_or_else(|e| panic!("{}: {}", loc, e));
+            let body_end = LLVMGetInsertBlock(builder);
+            LLVMPositionBuilderBefore(builder, element);
+            let partial = LLVMBuildPhi(builder, LLVMTypeOf(value.0), b"partialtmp\0".as_ptr() as *const _);
+            LLVMPositionBuilderAtEnd(builder, body_end);
+            let next = build_reduce_op(&op[..], partial, value.0, builder);
+            LLVMBuildBr(builder, latch);
+            LLVMAddIncoming(partial, [reduce_identity(&op[..], LLVMTypeOf(value.0)), next].as_mut_ptr(), [entry, latch].as_mut_ptr(), 2);
+            LLVMPositionBuilderAtEnd(builder, exit);
+            let total = build_block_reduce(partial, &op[..], thread, threads, opts.block_threads.get(), &format!("reduce{}_{}", loc.line, loc.col), nv, context, module, builder);
+            // the first thread of every block combines the total of its block into the target atomically
+            if let Some((array, indices)) = target.split_first() {
+                let elem = element_type(&array.1).unwrap();
+                let func = LLVMGetBasicBlockParent(LLVMGetInsertBlock(builder));
+                let first = LLVMBuildICmp(builder, LLVMIntPredicate::LLVMIntEQ, thread, LLVMConstInt(LLVMInt64TypeInContext(context), 0, 0), b"firsttmp\0".as_ptr() as *const _);
+                let combine = LLVMAppendBasicBlockInContext(context, func, b"reducecombine\0".as_ptr() as *const _);
+                let combined = LLVMAppendBasicBlockInContext(context, func, b"reducecombined\0".as_ptr() as *const _);
+                LLVMBuildCondBr(builder, first, combine, combined);
+                LLVMPositionBuilderAtEnd(builder, combine);
+                let ptr = build_element_ptr(array, indices, "reduce", val_context, context, builder, nv, opts, &loc);
+                let total = gen_subtype_cast(&ty, &elem, total, context, builder);
+                build_atomic(&format!("@atomic_{}", op), ptr, &[total], &elem, &[], context, builder);
+                LLVMBuildBr(builder, combined);
+                LLVMPositionBuilderAtEnd(builder, combined);
+            }
+            (total, ty)
+        }
         _ => {
             (null_mut(), TyName::Unit)
         }
     }
 }
 
+pub struct CodegenOptions {
+    pub bounds_check: bool,
+    // f16 arithmetic uses the half instructions of the target instead of promoting to f32
+    pub native_half: bool,
+    // accesses the refinement check proved in range, they never need a runtime check
+    pub proven: HashSet<SrcLoc>,
+    // most threads in a block of the function being defined, after its launch bounds
+    pub block_threads: Cell<u64>,
+}
+
+// NVPTX and AMDGPU number the flat, global, shared (LDS) and constant spaces alike
 pub enum AddressSpace {
     Generic = 0,
     Global = 1,
+    Shared = 3,
     Constant = 4,
 }
 
-fn build_intrinsics(id: String, typed_params: Vec<(LLVMValueRef, TyName)>, _context: LLVMContextRef, _module: LLVMModuleRef, builder: LLVMBuilderRef) -> (LLVMValueRef, TyName) {
-    let mut params = typed_params.into_iter().map(|v| v.0).collect::<Vec<_>>();
+// Block-shared arrays with only numeric extents are allocated statically, parameter extents
+// or no shape at all take the dynamic shared memory sized at launch time
+pub(crate) fn is_dynamic_shared(ty: &TyName) -> bool {
+    match shape_of(ty) {
+        Some((extents, _)) => extents.iter().any(|e| e.parse::<u64>().is_err()),
+        None => true
+    }
+}
+
+fn collect_shared(body: &Vec<TypedExpr>, shared: &mut Vec<(String, TyName)>) {
+    for expr in body.iter() {
+        match &expr.0 {
+            BaseExpr::SharedDecl(name, ty) => shared.push((name.clone(), ty.clone())),
+            BaseExpr::IfExpr(_, body, next) | BaseExpr::Else(_, body, next) => {
+                collect_shared(body, shared);
+                if let Some(next) = next {
+                    collect_shared(&vec![(**next).clone()], shared);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn build_shared_decl(name: &str, ty: &TyName, context: LLVMContextRef, module: LLVMModuleRef, builder: LLVMBuilderRef) -> (LLVMValueRef, TyName) {
+    let elem = element_type(ty).unwrap_or_else(|| panic!("Shared memory `{}` must be an array, {:?} given", name, ty));
+    let elem_type = map_type(&elem, context, true);
+    unsafe {
+        let func = LLVMGetBasicBlockParent(LLVMGetInsertBlock(builder));
+        let global_name = CString::new(format!("{}_{}", Context::get_value_name(func), name)).unwrap();
+        let dynamic = is_dynamic_shared(ty);
+        let array_type = if dynamic {
+            LLVMArrayType(elem_type, 0)
+        } else {
+            LLVMArrayType(elem_type, shape_of(ty).unwrap().0.iter().map(|e| e.parse::<u32>().unwrap()).product())
+        };
+        let global = LLVMAddGlobalInAddressSpace(module, array_type, global_name.as_ptr(), AddressSpace::Shared as u32);
+        if dynamic {
+            // every dynamic shared array starts at the beginning of the launch-sized region
+            LLVMSetLinkage(global, LLVMLinkage::LLVMExternalLinkage);
+            LLVMSetAlignment(global, 16);
+        } else {
+            LLVMSetLinkage(global, LLVMLinkage::LLVMInternalLinkage);
+            LLVMSetInitializer(global, LLVMGetUndef(array_type));
+        }
+        let zero = LLVMConstInt(LLVMInt64TypeInContext(context), 0, 0);
+        let ptr = LLVMConstInBoundsGEP(global, [zero, zero].as_mut_ptr(), 2);
+        (ptr, if is_mutable(ty) { ty.clone() } else { TyName::MutBind(Box::new(ty.clone())) })
+    }
+}
+
+fn build_intrinsics(id: String, typed_params: Vec<(LLVMValueRef, TyName)>, options: Vec<String>, _context: LLVMContextRef, _module: LLVMModuleRef, builder: LLVMBuilderRef, val_context: &Vec<HashMap<String, (LLVMValueRef, TyName)>>, nv: &NVIntrinsics, opts: &CodegenOptions, loc: &SrcLoc) -> (LLVMValueRef, TyName) {
+    let params = typed_params.iter().map(|v| v.0).collect::<Vec<_>>();
     //println!("{}", id);
     match &id[..] {
         "@load" => unsafe {
-            let ptr = LLVMBuildGEP(builder, params[0], &mut (params[1].clone()) as *mut _, 1, b"loadtmp\0".as_ptr() as *mut _);
+            let ptr = build_element_ptr(&typed_params[0], &typed_params[1..], &id[..], val_context, _context, builder, nv, opts, loc);
             let load_val = LLVMBuildLoad(builder, ptr, b"tmp\0".as_ptr() as *mut _);
-            let val_type = LLVMTypeOf(load_val);
-            (load_val, dbg!(reverse_type(val_type, _context)))
+            let val_type = element_type(&typed_params[0].1).unwrap_or_else(|| reverse_type(LLVMTypeOf(load_val), _context));
+            set_vector_alignment(load_val, &val_type);
+            (load_val, val_type)
         }
         "@store" => unsafe {
-            assert!(LLVMGetPointerAddressSpace(LLVMTypeOf(params[0])) != AddressSpace::Constant as u32);
-            let ptr = LLVMBuildGEP(builder, params[0], &mut (params[1].clone()) as *mut _, 1, b"loadtmp\0".as_ptr() as *mut _);
-            (LLVMBuildStore(builder, params[2], ptr), TyName::Unit)
+            let (value, value_type) = typed_params[params.len() - 1].clone();
+            let value = match element_type(&typed_params[0].1) {
+                Some(elem) => gen_subtype_cast(&value_type, &elem, value, _context, builder),
+                None => value
+            };
+            let ptr = build_element_ptr(&typed_params[0], &typed_params[1..params.len() - 1], &id[..], val_context, _context, builder, nv, opts, loc);
+            let store = LLVMBuildStore(builder, value, ptr);
+            set_vector_alignment(store, &reverse_type(LLVMTypeOf(value), _context));
+            (store, TyName::Unit)
+        }
+        "@len" => unsafe {
+            let extents = shape_of(&typed_params[0].1).map(|s| s.0)
+                .unwrap_or_else(|| panic!("{}: Length of array of type {:?} is unknown, declare its shape as `[T; n]`", loc, typed_params[0].1));
+            let extents: Vec<_> = extents.iter().map(|e| build_extent(e, val_context, _context, builder)).collect();
+            let i64_type = TyName::NameBind(String::from("i64"));
+            if params.len() > 1 {
+                if LLVMIsConstant(params[1]) == 0 {
+                    panic!("{}: The dimension of `@len` must be a constant", loc);
+                }
+                let dim = LLVMConstIntGetSExtValue(params[1]);
+                if dim < 0 || dim as usize >= extents.len() {
+                    panic!("{}: Dimension {} is out of range for a rank {} array", loc, dim, extents.len());
+                }
+                return (extents[dim as usize], i64_type);
+            }
+            (extents.into_iter().fold(LLVMConstInt(LLVMInt64TypeInContext(_context), 1, 0), |total, extent|
+                LLVMBuildMul(builder, total, extent, b"lentmp\0".as_ptr() as *mut _)), i64_type)
+        }
+        "@sync" => unsafe {
+            (LLVMBuildCall(builder, nv.sync_thread, [].as_mut_ptr(), 0, b"\0".as_ptr() as *const _), TyName::Unit)
+        }
+        _ if id.starts_with("@atomic_") => {
+            let operands = lookup(&id[..]).unwrap().operands;
+            let elem = element_type(&typed_params[0].1).unwrap();
+            let values: Vec<_> = typed_params[params.len() - operands..].iter()
+                .map(|(value, ty)| gen_subtype_cast(ty, &elem, *value, _context, builder)).collect();
+            let ptr = build_element_ptr(&typed_params[0], &typed_params[1..params.len() - operands], &id[..], val_context, _context, builder, nv, opts, loc);
+            (build_atomic(&id[..], ptr, &values, &elem, &options, _context, builder), elem)
+        }
+        "@thread_idx" | "@block_idx" | "@block_dim" | "@grid_dim" => unsafe {
+            if LLVMIsConstant(params[0]) == 0 {
+                panic!("{}: The dimension of `{}` must be a constant", loc, id);
+            }
+            let dim = LLVMConstIntGetSExtValue(params[0]);
+            let registers = match &id[..] {
+                "@thread_idx" => [nv.thread_x, nv.thread_y, nv.thread_z],
+                "@block_idx" => [nv.block_x, nv.block_y, nv.block_z],
+                "@block_dim" => [nv.block_dim_x, nv.block_dim_y, nv.block_dim_z],
+                _ => [nv.grid_dim_x, nv.grid_dim_y, nv.grid_dim_z],
+            };
+            if dim < 0 || dim > 2 {
+                panic!("{}: Dimension {} of `{}` is out of range, only 0 to 2 exist", loc, dim, id);
+            }
+            let val = LLVMBuildCall(builder, registers[dim as usize], [].as_mut_ptr(), 0, b"sregtmp\0".as_ptr() as *const _);
+            (LLVMBuildZExt(builder, val, LLVMInt64TypeInContext(_context), b"idxtmp\0".as_ptr() as *const _), TyName::NameBind(String::from("i64")))
+        }
+        "@lane_id" | "@shfl_up" | "@shfl_down" | "@shfl_xor" | "@shfl_idx" | "@vote_any" | "@vote_all" | "@ballot" if nv.lane_id.is_null() => {
+            panic!("{}: `{}` works on warps of 32 lanes, amdgcn wavefronts have 64", loc, id)
+        }
+        "@lane_id" => unsafe {
+            let val = LLVMBuildCall(builder, nv.lane_id, [].as_mut_ptr(), 0, b"sregtmp\0".as_ptr() as *const _);
+            (LLVMBuildZExt(builder, val, LLVMInt64TypeInContext(_context), b"idxtmp\0".as_ptr() as *const _), TyName::NameBind(String::from("i64")))
+        }
+        "@shfl_up" | "@shfl_down" | "@shfl_xor" | "@shfl_idx" => {
+            let ty = match &typed_params[0].1 { TyName::MutBind(inner) => (**inner).clone(), ty => ty.clone() };
+            (build_shuffle(&id[..], params[0], params[1], nv, _context, builder), ty)
+        }
+        "@vote_any" | "@vote_all" | "@ballot" => unsafe {
+            let (func, ty) = match &id[..] {
+                "@vote_any" => (nv.vote_any, "bool"),
+                "@vote_all" => (nv.vote_all, "bool"),
+                _ => (nv.ballot, "i32")
+            };
+            let mut args = [LLVMConstInt(LLVMInt32TypeInContext(_context), FULL_WARP, 0), params[0]];
+            (LLVMBuildCall(builder, func, args.as_mut_ptr(), 2, b"votetmp\0".as_ptr() as *const _), TyName::NameBind(String::from(ty)))
+        }
+        _ => panic!("{}: Intrinsic `{}` is registered but has no code generation", loc, id)
+    }
+}
+
+// Atomics are relaxed unless an ordering is given, the C API cannot name the `block` and `device`
+// scopes so every scope is lowered to the system one, which is stronger
+fn atomic_ordering(options: &[String]) -> LLVMAtomicOrdering {
+    match options.iter().find(|o| ORDERINGS.contains(&&o[..])).map(|o| &o[..]) {
+        Some("acquire") => LLVMAtomicOrdering::LLVMAtomicOrderingAcquire,
+        Some("release") => LLVMAtomicOrdering::LLVMAtomicOrderingRelease,
+        Some("acq_rel") => LLVMAtomicOrdering::LLVMAtomicOrderingAcquireRelease,
+        Some("seq_cst") => LLVMAtomicOrdering::LLVMAtomicOrderingSequentiallyConsistent,
+        _ => LLVMAtomicOrdering::LLVMAtomicOrderingMonotonic
+    }
+}
+
+// A failed compare and swap stores nothing, it cannot have release semantics
+fn failure_ordering(options: &[String]) -> LLVMAtomicOrdering {
+    match atomic_ordering(options) {
+        LLVMAtomicOrdering::LLVMAtomicOrderingRelease => LLVMAtomicOrdering::LLVMAtomicOrderingMonotonic,
+        LLVMAtomicOrdering::LLVMAtomicOrderingAcquireRelease => LLVMAtomicOrdering::LLVMAtomicOrderingAcquire,
+        ordering => ordering
+    }
+}
+
+// The integer type of the same width as an atomic element
+unsafe fn bits_type(ty: LLVMTypeRef, context: LLVMContextRef) -> LLVMTypeRef {
+    match LLVMGetTypeKind(ty) {
+        LLVMTypeKind::LLVMHalfTypeKind => LLVMInt16TypeInContext(context),
+        LLVMTypeKind::LLVMFloatTypeKind => LLVMInt32TypeInContext(context),
+        LLVMTypeKind::LLVMDoubleTypeKind => LLVMInt64TypeInContext(context),
+        _ => ty
+    }
+}
+
+// `cmpxchg` only takes integers, floats are swapped through their bits
+unsafe fn build_cmpxchg(ptr: LLVMValueRef, cmp: LLVMValueRef, new: LLVMValueRef, options: &[String], context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    let ty = LLVMTypeOf(cmp);
+    let bits = bits_type(ty, context);
+    let ptr = LLVMBuildBitCast(builder, ptr, LLVMPointerType(bits, LLVMGetPointerAddressSpace(LLVMTypeOf(ptr))), b"bitsptrtmp\0".as_ptr() as *const _);
+    let cmp = LLVMBuildBitCast(builder, cmp, bits, b"bitstmp\0".as_ptr() as *const _);
+    let new = LLVMBuildBitCast(builder, new, bits, b"bitstmp\0".as_ptr() as *const _);
+    let pair = LLVMBuildAtomicCmpXchg(builder, ptr, cmp, new, atomic_ordering(options), failure_ordering(options), 0);
+    let old = LLVMBuildExtractValue(builder, pair, 0, b"oldtmp\0".as_ptr() as *const _);
+    LLVMBuildBitCast(builder, old, ty, b"oldtmp\0".as_ptr() as *const _)
+}
+
+// Float minimum and maximum have no `atomicrmw` operation, they retry a compare and swap until no other thread interferes
+unsafe fn build_cas_loop(ptr: LLVMValueRef, value: LLVMValueRef, keep: LLVMRealPredicate, options: &[String], context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    let ty = LLVMTypeOf(value);
+    let bits = bits_type(ty, context);
+    let bits_ptr = LLVMBuildBitCast(builder, ptr, LLVMPointerType(bits, LLVMGetPointerAddressSpace(LLVMTypeOf(ptr))), b"bitsptrtmp\0".as_ptr() as *const _);
+    let first = LLVMBuildLoad(builder, bits_ptr, b"firsttmp\0".as_ptr() as *const _);
+    let entry = LLVMGetInsertBlock(builder);
+    let func = LLVMGetBasicBlockParent(entry);
+    let retry = LLVMAppendBasicBlockInContext(context, func, b"casloop\0".as_ptr() as *const _);
+    let done = LLVMAppendBasicBlockInContext(context, func, b"casdone\0".as_ptr() as *const _);
+    LLVMBuildBr(builder, retry);
+    LLVMPositionBuilderAtEnd(builder, retry);
+    let expected = LLVMBuildPhi(builder, bits, b"expectedtmp\0".as_ptr() as *const _);
+    let current = LLVMBuildBitCast(builder, expected, ty, b"currenttmp\0".as_ptr() as *const _);
+    let keeps = LLVMBuildFCmp(builder, keep, value, current, b"keeptmp\0".as_ptr() as *const _);
+    let new = LLVMBuildSelect(builder, keeps, value, current, b"newtmp\0".as_ptr() as *const _);
+    let new = LLVMBuildBitCast(builder, new, bits, b"bitstmp\0".as_ptr() as *const _);
+    let pair = LLVMBuildAtomicCmpXchg(builder, bits_ptr, expected, new, atomic_ordering(options), failure_ordering(options), 0);
+    let seen = LLVMBuildExtractValue(builder, pair, 0, b"seentmp\0".as_ptr() as *const _);
+    let swapped = LLVMBuildExtractValue(builder, pair, 1, b"swappedtmp\0".as_ptr() as *const _);
+    LLVMBuildCondBr(builder, swapped, done, retry);
+    LLVMAddIncoming(expected, [first, seen].as_mut_ptr(), [entry, retry].as_mut_ptr(), 2);
+    LLVMPositionBuilderAtEnd(builder, done);
+    LLVMBuildBitCast(builder, seen, ty, b"oldtmp\0".as_ptr() as *const _)
+}
+
+// Lowers an `@atomic_` intrinsic on the element at `ptr`, returning the element's previous value
+fn build_atomic(id: &str, ptr: LLVMValueRef, values: &[LLVMValueRef], elem: &TyName, options: &[String], context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    let float = match elem { TyName::NameBind(name) => is_float_name(&name[..]), _ => false };
+    let ordering = atomic_ordering(options);
+    unsafe {
+        let op = match (id, float) {
+            ("@atomic_cas", _) => return build_cmpxchg(ptr, values[0], values[1], options, context, builder),
+            ("@atomic_min", true) => return build_cas_loop(ptr, values[0], LLVMRealPredicate::LLVMRealOLT, options, context, builder),
+            ("@atomic_max", true) => return build_cas_loop(ptr, values[0], LLVMRealPredicate::LLVMRealOGT, options, context, builder),
+            ("@atomic_add", true) => ATOMIC_RMW_FADD,
+            ("@atomic_add", false) => LLVMAtomicRMWBinOp::LLVMAtomicRMWBinOpAdd as c_uint,
+            ("@atomic_min", false) => LLVMAtomicRMWBinOp::LLVMAtomicRMWBinOpMin as c_uint,
+            ("@atomic_max", false) => LLVMAtomicRMWBinOp::LLVMAtomicRMWBinOpMax as c_uint,
+            ("@atomic_and", _) => LLVMAtomicRMWBinOp::LLVMAtomicRMWBinOpAnd as c_uint,
+            ("@atomic_or", _) => LLVMAtomicRMWBinOp::LLVMAtomicRMWBinOpOr as c_uint,
+            ("@atomic_xor", _) => LLVMAtomicRMWBinOp::LLVMAtomicRMWBinOpXor as c_uint,
+            _ => LLVMAtomicRMWBinOp::LLVMAtomicRMWBinOpXchg as c_uint
+        };
+        LLVMBuildAtomicRMW(builder, op, ptr, values[0], ordering, 0)
+    }
+}
+
+// Every lane of the warp takes part in shuffles and votes
+const FULL_WARP: u64 = 0xffffffff;
+
+// The shuffles move 32 bits, wider values are split into two halves shuffled alike
+fn build_shuffle(id: &str, value: LLVMValueRef, lane: LLVMValueRef, nv: &NVIntrinsics, context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    // lanes past the end of the warp keep their own value, below its start for `up`
+    let (int_func, float_func, clamp) = match id {
+        "@shfl_up" => (nv.shfl_up_i32, nv.shfl_up_f32, 0),
+        "@shfl_down" => (nv.shfl_down_i32, nv.shfl_down_f32, 31),
+        "@shfl_xor" => (nv.shfl_bfly_i32, nv.shfl_bfly_f32, 31),
+        _ => (nv.shfl_idx_i32, nv.shfl_idx_f32, 31)
+    };
+    unsafe {
+        let i32_type = LLVMInt32TypeInContext(context);
+        let lane = LLVMBuildIntCast(builder, lane, i32_type, b"lanetmp\0".as_ptr() as *const _);
+        let shuffle = |func: LLVMValueRef, value: LLVMValueRef| {
+            let mut args = [LLVMConstInt(i32_type, FULL_WARP, 0), value, lane, LLVMConstInt(i32_type, clamp, 0)];
+            LLVMBuildCall(builder, func, args.as_mut_ptr(), 4, b"shfltmp\0".as_ptr() as *const _)
+        };
+        let ty = LLVMTypeOf(value);
+        match LLVMGetTypeKind(ty) {
+            LLVMTypeKind::LLVMFloatTypeKind => shuffle(float_func, value),
+            LLVMTypeKind::LLVMIntegerTypeKind if LLVMGetIntTypeWidth(ty) == 32 => shuffle(int_func, value),
+            _ => {
+                let halves_type = LLVMVectorType(i32_type, 2);
+                let mut halves = LLVMBuildBitCast(builder, value, halves_type, b"halvestmp\0".as_ptr() as *const _);
+                for k in 0..2 {
+                    let index = LLVMConstInt(i32_type, k, 0);
+                    let half = shuffle(int_func, LLVMBuildExtractElement(builder, halves, index, b"halftmp\0".as_ptr() as *const _));
+                    halves = LLVMBuildInsertElement(builder, halves, half, index, b"halvestmp\0".as_ptr() as *const _);
+                }
+                LLVMBuildBitCast(builder, halves, ty, b"shfltmp\0".as_ptr() as *const _)
+            }
         }
-        _ => (null_mut(), TyName::Unit)
     }
 }
 
-fn build_trivial_body(decl: Vec<BaseExpr>, module_decl: &HashMap<String, Vec<(LLVMValueRef, TyName)>>, expected_ret: TyName, context: LLVMContextRef, module: LLVMModuleRef, builder: LLVMBuilderRef, val_context: &mut Vec<HashMap<String, (LLVMValueRef, TyName)>>) {
+// The number of threads of the grid along `dim`, ntid * nctaid
+fn build_grid_extent(dim: usize, nv: &NVIntrinsics, context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    let (block_dim, grid_dim) = match dim {
+        1 => (nv.block_dim_y, nv.grid_dim_y),
+        2 => (nv.block_dim_z, nv.grid_dim_z),
+        _ => (nv.block_dim_x, nv.grid_dim_x)
+    };
+    unsafe {
+        let read = |func: LLVMValueRef, tmp: &[u8]| {
+            let val = LLVMBuildCall(builder, func, [].as_mut_ptr(), 0, tmp.as_ptr() as *const _);
+            LLVMBuildZExt(builder, val, LLVMInt64TypeInContext(context), tmp.as_ptr() as *const _)
+        };
+        LLVMBuildNUWMul(builder, read(block_dim, b"ntidtmp\0"), read(grid_dim, b"nctaidtmp\0"), b"stridetmp\0".as_ptr() as *const _)
+    }
+}
+
+// Loops over `0..n`, starting at `start` and moving on by `stride`, as `parfun<x in n>` does over the whole grid.
+// Returns the index within the loop body, the block continuing with the next element and the block after the loop
+fn build_grid_stride(start: LLVMValueRef, stride: LLVMValueRef, range: (LLVMValueRef, TyName), func: &str, name: &str, context: LLVMContextRef, builder: LLVMBuilderRef) -> (LLVMValueRef, LLVMBasicBlockRef, LLVMBasicBlockRef) {
+    let i64_type = TyName::NameBind(String::from("i64"));
+    if !subtype_check(&range.1, &i64_type, &mut 0) || range.1 == TyName::NameBind(String::from("bool")) {
+        panic!("Kernel `{}`: the range of `{}` must be an integer, {:?} given", func, name, range.1);
+    }
+    unsafe {
+        let range = gen_subtype_cast(&range.1, &i64_type, range.0, context, builder);
+        let entry = LLVMGetInsertBlock(builder);
+        let parent = LLVMGetBasicBlockParent(entry);
+        let header = LLVMAppendBasicBlockInContext(context, parent, b"stride\0".as_ptr() as *const _);
+        let body = LLVMAppendBasicBlockInContext(context, parent, b"element\0".as_ptr() as *const _);
+        let latch = LLVMAppendBasicBlockInContext(context, parent, b"next\0".as_ptr() as *const _);
+        let exit = LLVMAppendBasicBlockInContext(context, parent, b"strided\0".as_ptr() as *const _);
+        LLVMBuildBr(builder, header);
+        LLVMPositionBuilderAtEnd(builder, header);
+        let cstring = CString::new(name).unwrap();
+        let index = LLVMBuildPhi(builder, LLVMInt64TypeInContext(context), cstring.as_ptr());
+        let inside = LLVMBuildICmp(builder, LLVMIntPredicate::LLVMIntSLT, index, range, b"insidetmp\0".as_ptr() as *const _);
+        LLVMBuildCondBr(builder, inside, body, exit);
+        LLVMPositionBuilderAtEnd(builder, latch);
+        let next = LLVMBuildNUWAdd(builder, index, stride, b"nexttmp\0".as_ptr() as *const _);
+        LLVMBuildBr(builder, header);
+        LLVMAddIncoming(index, [start, next].as_mut_ptr(), [entry, latch].as_mut_ptr(), 2);
+        LLVMPositionBuilderAtEnd(builder, body);
+        (index, latch, exit)
+    }
+}
+
+// The index of a thread in the whole grid along `dim`, ctaid * ntid + tid
+fn build_global_index(dim: usize, name: &str, nv: &NVIntrinsics, context: LLVMContextRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    let (block, block_dim, thread) = match dim {
+        0 => (nv.block_x, nv.block_dim_x, nv.thread_x),
+        1 => (nv.block_y, nv.block_dim_y, nv.thread_y),
+        2 => (nv.block_z, nv.block_dim_z, nv.thread_z),
+        _ => (nv.block_x, nv.block_dim_x, nv.thread_x)
+    };
+    unsafe {
+        // indices are declared as i64, the special registers are only 32 bits wide
+        let i64_type = LLVMInt64TypeInContext(context);
+        let read = |func: LLVMValueRef, tmp: &[u8]| {
+            let val = LLVMBuildCall(builder, func, [].as_mut_ptr(), 0, tmp.as_ptr() as *const _);
+            LLVMBuildZExt(builder, val, i64_type, tmp.as_ptr() as *const _)
+        };
+        let block = read(block, b"ctaidtmp\0");
+        let block_dim = read(block_dim, b"ntidtmp\0");
+        let thread = read(thread, b"tidtmp\0");
+        let cstring = CString::new(name).unwrap();
+        let offset = LLVMBuildNUWMul(builder, block, block_dim, b"offsettmp\0".as_ptr() as *const _);
+        LLVMBuildNUWAdd(builder, offset, thread, cstring.as_ptr())
+    }
+}
+
+// Thread and block numbers flattened over the three dimensions, followed by the number of threads
+// of a block and the number of blocks of the grid
+fn build_linear_ids(nv: &NVIntrinsics, context: LLVMContextRef, builder: LLVMBuilderRef) -> (LLVMValueRef, LLVMValueRef, LLVMValueRef, LLVMValueRef) {
+    unsafe {
+        let read = |func: LLVMValueRef, tmp: &[u8]| {
+            let val = LLVMBuildCall(builder, func, [].as_mut_ptr(), 0, tmp.as_ptr() as *const _);
+            LLVMBuildZExt(builder, val, LLVMInt64TypeInContext(context), tmp.as_ptr() as *const _)
+        };
+        // (z * ny + y) * nx + x, out of nx * ny * nz
+        let flatten = |ids: [LLVMValueRef; 3], dims: [LLVMValueRef; 3]| {
+            let id = LLVMBuildNUWAdd(builder, LLVMBuildNUWMul(builder, ids[2], dims[1], b"lineartmp\0".as_ptr() as *const _), ids[1], b"lineartmp\0".as_ptr() as *const _);
+            let id = LLVMBuildNUWAdd(builder, LLVMBuildNUWMul(builder, id, dims[0], b"lineartmp\0".as_ptr() as *const _), ids[0], b"lineartmp\0".as_ptr() as *const _);
+            let count = LLVMBuildNUWMul(builder, dims[0], dims[1], b"counttmp\0".as_ptr() as *const _);
+            (id, LLVMBuildNUWMul(builder, count, dims[2], b"counttmp\0".as_ptr() as *const _))
+        };
+        let (thread, threads) = flatten([read(nv.thread_x, b"tidtmp\0"), read(nv.thread_y, b"tidtmp\0"), read(nv.thread_z, b"tidtmp\0")],
+                                        [read(nv.block_dim_x, b"ntidtmp\0"), read(nv.block_dim_y, b"ntidtmp\0"), read(nv.block_dim_z, b"ntidtmp\0")]);
+        let (block, blocks) = flatten([read(nv.block_x, b"ctaidtmp\0"), read(nv.block_y, b"ctaidtmp\0"), read(nv.block_z, b"ctaidtmp\0")],
+                                      [read(nv.grid_dim_x, b"nctaidtmp\0"), read(nv.grid_dim_y, b"nctaidtmp\0"), read(nv.grid_dim_z, b"nctaidtmp\0")]);
+        (thread, threads, block, blocks)
+    }
+}
+
+// The value `op` leaves the other operand unchanged with, what threads without elements contribute
+unsafe fn reduce_identity(op: &str, ty: LLVMTypeRef) -> LLVMValueRef {
+    if let LLVMTypeKind::LLVMIntegerTypeKind = LLVMGetTypeKind(ty) {
+        let sign = 1u64 << (LLVMGetIntTypeWidth(ty) - 1);
+        return match op {
+            "min" => LLVMConstInt(ty, sign - 1, 0),
+            "max" => LLVMConstInt(ty, sign, 0),
+            _ => LLVMConstInt(ty, 0, 0)
+        };
+    }
+    match op {
+        "min" => LLVMConstReal(ty, f64::INFINITY),
+        "max" => LLVMConstReal(ty, f64::NEG_INFINITY),
+        _ => LLVMConstReal(ty, 0.0)
+    }
+}
+
+unsafe fn build_reduce_op(op: &str, lhs: LLVMValueRef, rhs: LLVMValueRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    let float = if let LLVMTypeKind::LLVMIntegerTypeKind = LLVMGetTypeKind(LLVMTypeOf(lhs)) { false } else { true };
+    let keep = match (op, float) {
+        ("add", true) => return LLVMBuildFAdd(builder, lhs, rhs, b"sumtmp\0".as_ptr() as *const _),
+        ("add", false) => return LLVMBuildAdd(builder, lhs, rhs, b"sumtmp\0".as_ptr() as *const _),
+        ("min", true) => LLVMBuildFCmp(builder, LLVMRealPredicate::LLVMRealOLT, lhs, rhs, b"keeptmp\0".as_ptr() as *const _),
+        ("max", true) => LLVMBuildFCmp(builder, LLVMRealPredicate::LLVMRealOGT, lhs, rhs, b"keeptmp\0".as_ptr() as *const _),
+        ("min", false) => LLVMBuildICmp(builder, LLVMIntPredicate::LLVMIntSLT, lhs, rhs, b"keeptmp\0".as_ptr() as *const _),
+        _ => LLVMBuildICmp(builder, LLVMIntPredicate::LLVMIntSGT, lhs, rhs, b"keeptmp\0".as_ptr() as *const _)
+    };
+    LLVMBuildSelect(builder, keep, lhs, rhs, b"keptmp\0".as_ptr() as *const _)
+}
+
+// Neither target runs more threads in a block, kernels without launch bounds may have as many
+pub const MAX_BLOCK_THREADS: u64 = 1024;
+
+// Most threads in a block of a kernel with these launch bounds
+pub(crate) fn block_threads(launch: &Vec<(String, Vec<i64>)>) -> u64 {
+    launch.iter().find(|l| l.0.eq("max_threads") || l.0.eq("threads"))
+        .map(|l| l.1.iter().product::<i64>() as u64)
+        .unwrap_or(MAX_BLOCK_THREADS)
+}
+
+// Tree reduction of one value per thread through shared memory, every step halves the threads still
+// combining and ends with a barrier. The scratch has a slot for each of the `block_threads` threads
+// a block may have at most. Every thread of the block gets the total
+unsafe fn build_block_reduce(value: LLVMValueRef, op: &str, thread: LLVMValueRef, threads: LLVMValueRef, block_threads: u64, name: &str, nv: &NVIntrinsics, context: LLVMContextRef, module: LLVMModuleRef, builder: LLVMBuilderRef) -> LLVMValueRef {
+    let i64_type = LLVMInt64TypeInContext(context);
+    let func = LLVMGetBasicBlockParent(LLVMGetInsertBlock(builder));
+    let global_name = CString::new(format!("{}_{}", Context::get_value_name(func), name)).unwrap();
+    let array_type = LLVMArrayType(LLVMTypeOf(value), block_threads as u32);
+    let scratch = LLVMAddGlobalInAddressSpace(module, array_type, global_name.as_ptr(), AddressSpace::Shared as u32);
+    LLVMSetLinkage(scratch, LLVMLinkage::LLVMInternalLinkage);
+    LLVMSetInitializer(scratch, LLVMGetUndef(array_type));
+    let slot = |index: LLVMValueRef| LLVMBuildInBoundsGEP(builder, scratch, [LLVMConstInt(i64_type, 0, 0), index].as_mut_ptr(), 2, b"slottmp\0".as_ptr() as *const _);
+    let sync = || LLVMBuildCall(builder, nv.sync_thread, [].as_mut_ptr(), 0, b"\0".as_ptr() as *const _);
+    LLVMBuildStore(builder, value, slot(thread));
+    sync();
+    let mut half = block_threads.next_power_of_two() / 2;
+    while half > 0 {
+        let partner = LLVMBuildNUWAdd(builder, thread, LLVMConstInt(i64_type, half, 0), b"partnertmp\0".as_ptr() as *const _);
+        let lower = LLVMBuildICmp(builder, LLVMIntPredicate::LLVMIntULT, thread, LLVMConstInt(i64_type, half, 0), b"lowertmp\0".as_ptr() as *const _);
+        let paired = LLVMBuildICmp(builder, LLVMIntPredicate::LLVMIntULT, partner, threads, b"pairedtmp\0".as_ptr() as *const _);
+        let active = LLVMBuildAnd(builder, lower, paired, b"activetmp\0".as_ptr() as *const _);
+        let step = LLVMAppendBasicBlockInContext(context, func, b"reducestep\0".as_ptr() as *const _);
+        let stepped = LLVMAppendBasicBlockInContext(context, func, b"reducestepped\0".as_ptr() as *const _);
+        LLVMBuildCondBr(builder, active, step, stepped);
+        LLVMPositionBuilderAtEnd(builder, step);
+        let own = LLVMBuildLoad(builder, slot(thread), b"owntmp\0".as_ptr() as *const _);
+        let other = LLVMBuildLoad(builder, slot(partner), b"othertmp\0".as_ptr() as *const _);
+        LLVMBuildStore(builder, build_reduce_op(op, own, other, builder), slot(thread));
+        LLVMBuildBr(builder, stepped);
+        LLVMPositionBuilderAtEnd(builder, stepped);
+        sync();
+        half /= 2;
+    }
+    let total = LLVMBuildLoad(builder, slot(LLVMConstInt(i64_type, 0, 0)), b"totaltmp\0".as_ptr() as *const _);
+    // the scratch is only overwritten once every thread read the total
+    sync();
+    total
+}
+
+// Each branch is generated in its own scope, branches that do not return fall through to `merge`
+fn build_if_chain(cond: Option<TypedExpr>, body: Vec<TypedExpr>, next: Option<Box<TypedExpr>>, merge: LLVMBasicBlockRef, module_decl: &HashMap<String, Vec<(LLVMValueRef, TyName)>>, expected_ret: TyName, context: LLVMContextRef, module: LLVMModuleRef, builder: LLVMBuilderRef, val_context: &mut Vec<HashMap<String, (LLVMValueRef, TyName)>>, nv: &NVIntrinsics, opts: &CodegenOptions, generics: &Generics) {
+    unsafe {
+        let func = LLVMGetBasicBlockParent(LLVMGetInsertBlock(builder));
+        let mut else_block = merge;
+        if let Some(cond) = cond {
+            let cond = build_recurse_expr(cond.0, module_decl, expected_ret.clone(), context, module, builder, val_context, nv, opts, generics);
+            if cond.1 != TyName::NameBind(String::from("bool")) {
+                panic!("Condition of `if` must be a bool, {:?} given", cond.1);
+            }
+            let then_block = LLVMAppendBasicBlockInContext(context, func, b"then\0".as_ptr() as *const _);
+            if next.is_some() {
+                else_block = LLVMAppendBasicBlockInContext(context, func, b"else\0".as_ptr() as *const _);
+            }
+            LLVMBuildCondBr(builder, cond.0, then_block, else_block);
+            LLVMPositionBuilderAtEnd(builder, then_block);
+        }
+        val_context.push(HashMap::new());
+        build_trivial_body(body.into_iter().map(|v| v.0).collect(), module_decl, expected_ret.clone(), context, module, builder, val_context, nv, opts, generics);
+        val_context.pop();
+        if LLVMGetBasicBlockTerminator(LLVMGetInsertBlock(builder)).is_null() {
+            LLVMBuildBr(builder, merge);
+        }
+        if let Some(next) = next {
+            if let BaseExpr::Else(cond, body, next) = next.0 {
+                LLVMPositionBuilderAtEnd(builder, else_block);
+                build_if_chain(cond.map(|c| *c), body, next, merge, module_decl, expected_ret, context, module, builder, val_context, nv, opts, generics);
+            }
+        }
+    }
+}
+
+fn build_trivial_body(decl: Vec<BaseExpr>, module_decl: &HashMap<String, Vec<(LLVMValueRef, TyName)>>, expected_ret: TyName, context: LLVMContextRef, module: LLVMModuleRef, builder: LLVMBuilderRef, val_context: &mut Vec<HashMap<String, (LLVMValueRef, TyName)>>, nv: &NVIntrinsics, opts: &CodegenOptions, generics: &Generics) {
     for expr in decl {
-        build_recurse_expr(expr, module_decl, expected_ret.clone(), context, module, builder, val_context);
+        build_recurse_expr(expr, module_decl, expected_ret.clone(), context, module, builder, val_context, nv, opts, generics);
     }
 }
 
+// NVVM annotations for the launch bounds of a kernel, the backend turns them into PTX performance directives
+//     max_threads(x, y, z)   .maxntid       most threads in a block
+//     threads(x, y, z)       .reqntid       exact number of threads in a block
+//     min_blocks(n)          .minnctapersm  blocks that should fit on one multiprocessor, needs max_threads
+//     max_registers(n)       .maxnreg       registers per thread
+fn launch_annotations(launch: &Vec<(String, Vec<i64>)>) -> Result<Vec<(String, i64)>, String> {
+    let mut annotations = vec![];
+    for (name, values) in launch.iter() {
+        if launch.iter().filter(|l| l.0.eq(name)).count() > 1 {
+            return Err(format!("`{}` is given twice", name));
+        }
+        if let Some(v) = values.iter().find(|v| **v <= 0) {
+            return Err(format!("`{}` expects positive values, {} given", name, v));
+        }
+        let (key, dims) = match &name[..] {
+            "max_threads" => ("maxntid", 3),
+            "threads" => ("reqntid", 3),
+            "min_blocks" => ("minctasm", 1),
+            "max_registers" => ("maxnreg", 1),
+            _ => return Err(format!("unknown launch bound `{}`, expected max_threads, threads, min_blocks or max_registers", name))
+        };
+        if values.len() > dims {
+            return Err(format!("`{}` takes at most {} value{}, {} given", name, dims, if dims == 1 { "" } else { "s" }, values.len()));
+        }
+        if dims == 1 {
+            annotations.push((String::from(key), values[0]));
+        } else {
+            annotations.extend(values.iter().zip(["x", "y", "z"].iter()).map(|(v, d)| (format!("{}{}", key, d), *v)));
+        }
+    }
+    let given = |name: &str| launch.iter().any(|l| l.0.eq(name));
+    if given("max_threads") && given("threads") {
+        return Err(String::from("`max_threads` and `threads` cannot be given together"));
+    }
+    if given("min_blocks") && !given("max_threads") {
+        return Err(String::from("`min_blocks` needs `max_threads` as well"));
+    }
+    Ok(annotations)
+}
+
+// amdgcn has no NVVM annotations, the launch bounds become string attributes of the kernel
+//     max_threads(x, y, z)   amdgpu-flat-work-group-size  1 to x * y * z threads in a block
+//     threads(x, y, z)       amdgpu-flat-work-group-size  exactly x * y * z threads in a block
+//     max_registers(n)       amdgpu-num-vgpr              vector registers per thread
+//     min_blocks(n)          amdgpu-waves-per-eu          waves each SIMD should fit, for n blocks of max_threads
+//                                                         threads on a compute unit, in waves of 64 over its 4 SIMDs
+fn amdgcn_launch_attributes(launch: &Vec<(String, Vec<i64>)>) -> Result<Vec<(String, String)>, String> {
+    launch_annotations(launch)?;
+    let mut attributes = vec![];
+    for (name, values) in launch.iter() {
+        let threads: i64 = values.iter().product();
+        match &name[..] {
+            "max_threads" => attributes.push((String::from("amdgpu-flat-work-group-size"), format!("1,{}", threads))),
+            "threads" => attributes.push((String::from("amdgpu-flat-work-group-size"), format!("{},{}", threads, threads))),
+            "max_registers" => attributes.push((String::from("amdgpu-num-vgpr"), values[0].to_string())),
+            "min_blocks" => {
+                // `launch_annotations` made sure max_threads is given
+                let waves = values[0] * ((block_threads(launch) as i64 + 63) / 64);
+                attributes.push((String::from("amdgpu-waves-per-eu"), ((waves + 3) / 4).to_string()));
+            }
+            _ => {}
+        }
+    }
+    Ok(attributes)
+}
+
 pub(crate) fn llvm_declare_func(decl: BaseExpr, context: LLVMContextRef, module: LLVMModuleRef, _builder: LLVMBuilderRef) -> (LLVMValueRef, String) {
     if let BaseExpr::FuncDecl {
-        ident, para_in: _, is_par, params, ret, body: _
+        ident, para_in, ranges: _, is_par, launch, mut params, ret, body: _
     } = decl {
-        let ret_type = map_type(&ret, context, false, is_par);
-        let mut param_types: Vec<_> = params.iter().map(|p| map_type(&p.1, context, false, is_par)).collect();
+        if is_par {
+            params = shape_kernel_arrays(params);
+        }
+        params.extend(hidden_params(&params));
+        params.extend(folded_params(&para_in));
+        let ret_type = map_type(&ret, context, is_par);
+        let mut param_types: Vec<_> = params.iter().map(|p| map_type(&p.1, context, is_par)).collect();
         let func = unsafe { LLVMFunctionType(ret_type, param_types.deref_mut().as_mut_ptr(), param_types.len() as u32, 0) };
         let ident_str = CString::new(ident.clone());
         let func_obj = unsafe { LLVMAddFunction(module, ident_str.unwrap().as_ptr(), func) };
-        llvm_set_param_name(params, func_obj);
         if is_par {
-            unsafe {
-                let node = LLVMMDNodeInContext(context,
-                                               [func_obj, LLVMMDString(b"kernel\0".as_ptr() as *mut _, 6),
-                                                   LLVMConstInt(LLVMInt32Type(), 1, 1)].as_mut_ptr(), 3);
-                LLVMAddNamedMetadataOperand(module, b"nvvm.annotations\0".as_ptr() as *mut i8, node);
+            // Immutable kernel arrays are never written through, so the backend may use read-only cached loads.
+            // The host may pass the same buffer as a `mut` array of the same element type, they are only
+            // known not to alias when there is no such array
+            let written: Vec<_> = params.iter().filter(|p| is_mutable(&p.1)).filter_map(|p| element_type(&p.1)).collect();
+            for (i, param) in params.iter().enumerate() {
+                if let TyName::Array(elem) | TyName::Dense(elem, ..) = &param.1 {
+                    add_param_attribute(func_obj, i as u32, "readonly", context);
+                    if !written.contains(&**elem) {
+                        add_param_attribute(func_obj, i as u32, "noalias", context);
+                    }
+                }
+            }
+        }
+        llvm_set_param_name(params, func_obj);
+        if is_par && Target::of_module(module) == Target::Amdgcn {
+            unsafe { LLVMSetFunctionCallConv(func_obj, AMDGPU_KERNEL_CALL_CONV) };
+            for (key, value) in amdgcn_launch_attributes(&launch).unwrap_or_else(|e| panic!("Kernel `{}`: {}", ident, e)) {
+                add_function_attribute(func_obj, &key[..], &value[..], context);
+            }
+        } else if is_par {
+            let mut annotations = vec![(String::from("kernel"), 1)];
+            annotations.extend(launch_annotations(&launch).unwrap_or_else(|e| panic!("Kernel `{}`: {}", ident, e)));
+            for (key, value) in annotations {
+                unsafe {
+                    let node = LLVMMDNodeInContext(context,
+                                                   [func_obj, LLVMMDString(key.as_ptr() as *const _, key.len() as u32),
+                                                       LLVMConstInt(LLVMInt32Type(), value as u64, 1)].as_mut_ptr(), 3);
+                    LLVMAddNamedMetadataOperand(module, b"nvvm.annotations\0".as_ptr() as *mut i8, node);
+                }
             }
         }
         return (func_obj, ident);
     } else {
         if let BaseExpr::IntrinsicsFuncDecl(ident, params, ret, _body) = decl {
-            let ret_type = map_type(&ret, context, false, false);
-            let mut param_types: Vec<_> = params.iter().map(|p| map_type(&p.1, context, false, false)).collect();
+            let ret_type = map_type(&ret, context, false);
+            let mut param_types: Vec<_> = params.iter().map(|p| map_type(&p.1, context, false)).collect();
             let func = unsafe { LLVMFunctionType(ret_type, param_types.deref_mut().as_mut_ptr(), param_types.len() as u32, 0) };
             let ident_str = CString::new(ident.clone());
             let func_obj = unsafe { LLVMAddFunction(module, ident_str.unwrap().as_ptr(), func) };
@@ -260,8 +1542,8 @@ pub(crate) fn llvm_declare_func(decl: BaseExpr, context: LLVMContextRef, module:
             return (func_obj, ident);
         } else {
             if let BaseExpr::FuncVirtualDecl(ident, params, ret) = decl {
-                let ret_type = map_type(&ret, context, false, false);
-                let mut param_types: Vec<_> = params.iter().map(|p| map_type(&p.1, context, false, false)).collect();
+                let ret_type = map_type(&ret, context, false);
+                let mut param_types: Vec<_> = params.iter().map(|p| map_type(&p.1, context, false)).collect();
                 let func = unsafe { LLVMFunctionType(ret_type, param_types.deref_mut().as_mut_ptr(), param_types.len() as u32, 0) };
                 let ident_str = CString::new(ident.clone());
                 let func_obj = unsafe { LLVMAddFunction(module, ident_str.unwrap().as_ptr(), func) };
@@ -273,6 +1555,48 @@ pub(crate) fn llvm_declare_func(decl: BaseExpr, context: LLVMContextRef, module:
     }
 }
 
+// Records are built by a function named after them, taking every field in order
+pub(crate) fn llvm_record_ctor(ty: &TyName, context: LLVMContextRef, module: LLVMModuleRef, builder: LLVMBuilderRef) -> (LLVMValueRef, TyName) {
+    if let TyName::Record(name, fields) = ty {
+        let params = fields.iter().map(|f| f.1.clone()).collect();
+        let (func_obj, _) = llvm_declare_func(BaseExpr::FuncVirtualDecl(name.clone(), fields.clone(), ty.clone()), context, module, builder);
+        unsafe {
+            LLVMPositionBuilderAtEnd(builder, LLVMAppendBasicBlockInContext(context, func_obj, b"entry\0".as_ptr() as *const _));
+            let mut record = LLVMGetUndef(map_type(ty, context, false));
+            for i in 0..fields.len() {
+                record = LLVMBuildInsertValue(builder, record, LLVMGetParam(func_obj, i as u32), i as u32, b"recordtmp\0".as_ptr() as *const _);
+            }
+            LLVMBuildRet(builder, record);
+            LLVMVerifyFunction(func_obj, LLVMAbortProcessAction);
+        }
+        return (func_obj, TyName::Arrow(Box::new(TyName::Tuple(params)), Box::new(ty.clone())));
+    }
+    panic!("Only records have constructors, {:?} given", ty)
+}
+
+// Constants are declared as arrays with numeric extents, the host fills them before launching,
+// e.g. with `cudaMemcpyToSymbol`, so their initializer is never assumed
+pub(crate) fn llvm_const_decl(name: &str, ty: &TyName, context: LLVMContextRef, module: LLVMModuleRef) -> (LLVMValueRef, TyName) {
+    let elem = element_type(ty).unwrap_or_else(|| panic!("Constant `{}` must be an array, {:?} given", name, ty));
+    let extents = shape_of(ty).map(|s| s.0).unwrap_or(vec![]);
+    let size = extents.iter().map(|e| e.parse::<u32>().ok()).product::<Option<u32>>().filter(|_| !extents.is_empty())
+        .unwrap_or_else(|| panic!("Constant `{}` must have numeric extents, e.g. `[f32; 16]`, {:?} given", name, ty));
+    unsafe {
+        let array_type = LLVMArrayType(map_type(&elem, context, true), size);
+        let global_name = CString::new(name).unwrap();
+        let global = LLVMAddGlobalInAddressSpace(module, array_type, global_name.as_ptr(), AddressSpace::Constant as u32);
+        LLVMSetInitializer(global, LLVMConstNull(array_type));
+        LLVMSetExternallyInitialized(global, 1);
+        let zero = LLVMConstInt(LLVMInt64TypeInContext(context), 0, 0);
+        (LLVMConstInBoundsGEP(global, [zero, zero].as_mut_ptr(), 2), ty.clone())
+    }
+}
+
+// Constants live next to the functions of the module, they are the only values that are not functions
+pub(crate) fn module_const(module_decl: &HashMap<String, Vec<(LLVMValueRef, TyName)>>, id: &str) -> Option<(LLVMValueRef, TyName)> {
+    module_decl.get(id)?.iter().find(|(value, _)| !value.is_null() && unsafe { LLVMIsAFunction(*value) }.is_null()).cloned()
+}
+
 fn llvm_set_param_name(params: Vec<(String, TyName)>, func_obj: LLVMValueRef) {
     for i in 0..params.len() {
         let param_str = CString::new(params[i].0.clone());
@@ -287,36 +1611,137 @@ pub(crate) fn llvm_embedded_ir(ident: String, params: Vec<(String, TyName)>, ret
     let params_str = params.into_iter().map(|param| {
         let type_name = unsafe {
             CStr::from_ptr(LLVMPrintTypeToString(
-                map_type(&param.1, context, false, false))).to_owned().into_string().unwrap()
+                map_type(&param.1, context, false))).to_owned().into_string().unwrap()
         };
         String::from(format!("{} %{}", type_name, param.0))
     }).collect::<Vec<String>>().join(", ");
     let ret_type_str = unsafe {
         CStr::from_ptr(LLVMPrintTypeToString(
-            map_type(&ret_type, context, false, false))).to_owned().into_string().unwrap()
+            map_type(&ret_type, context, false))).to_owned().into_string().unwrap()
     };
     String::from(format!("define {} @{}({}) {{\n{}\n}}", ret_type_str, ident, params_str, body))
 }
 
+// Embedded bodies may call LLVM intrinsics and libdevice functions, the IR parser needs them declared first.
+// Their signatures are read off the calls, `call float @llvm.sqrt.f32(float %a)` declares `float @llvm.sqrt.f32(float)`
+pub(crate) fn llvm_embedded_decls(body: &str) -> Vec<(String, String)> {
+    body.lines().filter_map(|line| {
+        let call = &line[line.find("call ")? + 5..];
+        let at = call.find(" @")?;
+        let callee = &call[at + 2..];
+        let open = callee.find('(')?;
+        let name = &callee[..open];
+        if !(name.starts_with("llvm.") || name.starts_with("__nv_")) {
+            return None;
+        }
+        let args = &callee[open + 1..callee.rfind(')')?];
+        let types: Vec<_> = args.split(',').filter_map(|arg| arg.trim().rsplitn(2, ' ').nth(1)).collect();
+        Some((name.to_string(), format!("declare {} @{}({})\n\n", &call[..at], name, types.join(", "))))
+    }).collect()
+}
+
+// libdevice is linked whole and made internal so that the functions nothing calls are dropped.
+// Math functions calling into a libdevice that was not given are removed when unused, and rejected otherwise.
+// On amdgcn the same goes for the NVVM intrinsics some of them call
+pub(crate) fn llvm_link_libdevice(module: LLVMModuleRef, libdevice: Option<String>, context: LLVMContextRef) {
+    unsafe {
+        if let Some(path) = libdevice {
+            let cpath = CString::new(path.clone()).unwrap();
+            let mut buffer = null_mut();
+            let mut message = null_mut();
+            if LLVMCreateMemoryBufferWithContentsOfFile(cpath.as_ptr(), &mut buffer, &mut message) != 0 {
+                panic!("Cannot read libdevice `{}`: {}", path, CStr::from_ptr(message).to_string_lossy());
+            }
+            let mut lib = null_mut();
+            if LLVMParseBitcodeInContext2(context, buffer, &mut lib) != 0 {
+                panic!("libdevice `{}` is not LLVM bitcode", path);
+            }
+            let mut defined = vec![];
+            let mut func = LLVMGetFirstFunction(lib);
+            while !func.is_null() {
+                if LLVMIsDeclaration(func) == 0 {
+                    defined.push(CString::new(Context::get_value_name(func)).unwrap());
+                }
+                func = LLVMGetNextFunction(func);
+            }
+            LLVMSetDataLayout(lib, LLVMGetDataLayout(module));
+            LLVMSetTarget(lib, LLVMGetTarget(module));
+            LLVMLinkModules2(module, lib);
+            for name in defined.iter() {
+                let func = LLVMGetNamedFunction(module, name.as_ptr());
+                if !func.is_null() {
+                    LLVMSetLinkage(func, LLVMLinkage::LLVMInternalLinkage);
+                }
+            }
+        }
+        let amdgcn = Target::of_module(module) == Target::Amdgcn;
+        let mut unresolved = vec![];
+        let mut func = LLVMGetFirstFunction(module);
+        while !func.is_null() {
+            let name = Context::get_value_name(func);
+            if LLVMIsDeclaration(func) != 0 && (name.starts_with("__nv_") || (amdgcn && name.starts_with("llvm.nvvm."))) {
+                unresolved.push(func);
+            }
+            func = LLVMGetNextFunction(func);
+        }
+        for decl in unresolved {
+            let mut callers = vec![];
+            let mut call = LLVMGetFirstUse(decl);
+            while !call.is_null() {
+                let caller = LLVMGetBasicBlockParent(LLVMGetInstructionParent(LLVMGetUser(call)));
+                if !callers.contains(&caller) {
+                    callers.push(caller);
+                }
+                call = LLVMGetNextUse(call);
+            }
+            for caller in callers {
+                if !LLVMGetFirstUse(caller).is_null() && amdgcn {
+                    panic!("`{}` calls `{}`, which only exists on nvptx", Context::get_value_name(caller), Context::get_value_name(decl));
+                }
+                if !LLVMGetFirstUse(caller).is_null() {
+                    panic!("`{}` calls `{}` from libdevice, compile with --libdevice <path>", Context::get_value_name(caller), Context::get_value_name(decl));
+                }
+                LLVMDeleteFunction(caller);
+            }
+            LLVMDeleteFunction(decl);
+        }
+    }
+}
+
 pub(crate) fn llvm_embedded_kernel_decl(ident: String, params: Vec<(String, TyName)>, ret_type: TyName, context: LLVMContextRef) -> String {
     let params_str = params.into_iter().map(|param| {
         let type_name = unsafe {
             CStr::from_ptr(LLVMPrintTypeToString(
-                map_type(&param.1, context, false, false))).to_owned().into_string().unwrap()
+                map_type(&param.1, context, false))).to_owned().into_string().unwrap()
         };
         String::from(format!("{} %{}", type_name, param.0))
     }).collect::<Vec<String>>().join(", ");
     let ret_type_str = unsafe {
         CStr::from_ptr(LLVMPrintTypeToString(
-            map_type(&ret_type, context, false, false))).to_owned().into_string().unwrap()
+            map_type(&ret_type, context, false))).to_owned().into_string().unwrap()
     };
     String::from(format!("declare {} @{}({})\n\n", ret_type_str, ident, params_str))
 }
 
-pub(crate) fn llvm_define_func(decl: TypedExpr, func_ref: LLVMValueRef, module_decl: &HashMap<String, Vec<(LLVMValueRef, TyName)>>, context: LLVMContextRef, module: LLVMModuleRef, builder: LLVMBuilderRef, nv: &NVIntrinsics) -> LLVMValueRef {
+pub(crate) fn llvm_define_func(decl: TypedExpr, func_ref: LLVMValueRef, module_decl: &HashMap<String, Vec<(LLVMValueRef, TyName)>>, context: LLVMContextRef, module: LLVMModuleRef, builder: LLVMBuilderRef, nv: &NVIntrinsics, opts: &CodegenOptions, generics: &Generics) -> LLVMValueRef {
     if let BaseExpr::FuncDecl {
-        ident: _, para_in, is_par, params, ret, body
+        ident, para_in, ranges, is_par, launch, mut params, ret, body
     } = decl.0 {
+        opts.block_threads.set(block_threads(&launch));
+        let mut shared = vec![];
+        collect_shared(&body, &mut shared);
+        if let Some((name, _)) = shared.first().filter(|_| !is_par) {
+            panic!("Shared memory `{}` can only be declared in a parfun, `{}` is a fun", name, ident);
+        }
+        let dynamic: Vec<_> = shared.iter().filter(|s| is_dynamic_shared(&s.1)).map(|s| format!("`{}`", s.0)).collect();
+        if dynamic.len() > 1 {
+            panic!("Kernel `{}` declares several launch-sized shared arrays {}, they would all start at the same address", ident, dynamic.join(", "));
+        }
+        if is_par {
+            params = shape_kernel_arrays(params);
+        }
+        params.extend(hidden_params(&params));
+        params.extend(folded_params(&para_in));
         let mut val_context = vec![HashMap::new()];
         let func_obj = func_ref;
         let mut base_var = HashMap::<String, (LLVMValueRef, TyName)>::new();
@@ -329,31 +1754,84 @@ pub(crate) fn llvm_define_func(decl: TypedExpr, func_ref: LLVMValueRef, module_d
             }
         }
         unsafe { LLVMPositionBuilderAtEnd(builder, LLVMAppendBasicBlockInContext(context, func_obj, b"entry\0".as_ptr() as *const _)); }
+        val_context.push(base_var);
+        let mut loops = vec![];
         if is_par {
-            unsafe {
-                let node = LLVMMDNodeInContext(context,
-                                               [func_obj, LLVMMDString(b"kernel\0".as_ptr() as *mut _, 6),
-                                                   LLVMConstInt(LLVMInt32Type(), 1, 1)].as_mut_ptr(), 3);
-                LLVMAddNamedMetadataOperand(module, b"nvvm.annotations\0".as_ptr() as *mut i8, node);
-                // Load index from intrinsics
-                let mut cnt = 0;
-                for par in para_in {
+            // Load index from intrinsics
+            let mut cnt = 0;
+            let folded = folded_params(&para_in);
+            let dims = para_in.len();
+            let mut remaining = None;
+            for (par, range) in para_in.into_iter().zip(ranges.into_iter()) {
+                if cnt >= 2 && !folded.is_empty() {
+                    if range.is_some() {
+                        panic!("Kernel `{}`: `{}` is folded onto z with the other indices after the second, it cannot loop over a range", ident, par);
+                    }
+                    let extent = build_extent(&folded[cnt - 2].0[..], &val_context, context, builder);
+                    let linear = *remaining.get_or_insert_with(|| build_global_index(2, "foldedtmp", nv, context, builder));
+                    let cstring = CString::new(par.clone()).unwrap();
+                    let val = unsafe {
+                        if cnt + 1 < dims {
+                            remaining = Some(LLVMBuildUDiv(builder, linear, extent, b"foldedtmp\0".as_ptr() as *const _));
+                            LLVMBuildURem(builder, linear, extent, cstring.as_ptr())
+                        } else {
+                            // the launch is rounded up to whole blocks, the threads past the last extent skip the body
+                            let func = LLVMGetBasicBlockParent(LLVMGetInsertBlock(builder));
+                            let inside = LLVMBuildICmp(builder, LLVMIntPredicate::LLVMIntULT, linear, extent, b"insidetmp\0".as_ptr() as *const _);
+                            let body = LLVMAppendBasicBlockInContext(context, func, b"folded\0".as_ptr() as *const _);
+                            let outside = LLVMAppendBasicBlockInContext(context, func, b"unfolded\0".as_ptr() as *const _);
+                            LLVMBuildCondBr(builder, inside, body, outside);
+                            LLVMPositionBuilderAtEnd(builder, outside);
+                            match loops.last() {
+                                Some((latch, _)) => LLVMBuildBr(builder, *latch),
+                                None => LLVMBuildRetVoid(builder)
+                            };
+                            LLVMPositionBuilderAtEnd(builder, body);
+                            LLVMSetValueName(linear, cstring.as_ptr());
+                            linear
+                        }
+                    };
                     if par.ne(&String::from("_")) {
-                        let cstring = CString::new(par.clone());
-                        let val = LLVMBuildCall(builder, match cnt {
-                            0 => nv.thread_x,
-                            1 => nv.thread_y,
-                            2 => nv.thread_z,
-                            _ => nv.thread_x
-                        }, [].as_mut_ptr(), 0, cstring.unwrap().as_ptr());
-                        base_var.insert(par, (val, TyName::NameBind(String::from("i64"))));
+                        val_context.last_mut().unwrap().insert(par, (val, TyName::NameBind(String::from("i64"))));
                     }
                     cnt = cnt + 1;
+                    continue;
+                }
+                let val = build_global_index(cnt, &par[..], nv, context, builder);
+                let val = match range {
+                    Some(range) => {
+                        let range = build_recurse_expr(range.0, module_decl, ret.clone(), context, module, builder, &mut val_context, nv, opts, generics);
+                        let stride = build_grid_extent(cnt, nv, context, builder);
+                        let (val, latch, exit) = build_grid_stride(val, stride, range, &ident[..], &par[..], context, builder);
+                        loops.push((latch, exit));
+                        // a `return` is done with the current element only
+                        val_context.last_mut().unwrap().insert(String::from("@continue"), (unsafe { LLVMBasicBlockAsValue(latch) }, TyName::Unit));
+                        val
+                    }
+                    None => val
+                };
+                if par.ne(&String::from("_")) {
+                    val_context.last_mut().unwrap().insert(par, (val, TyName::NameBind(String::from("i64"))));
                 }
+                cnt = cnt + 1;
+            }
+        }
+        build_trivial_body(body.into_iter().map(|v| v.0).collect(), module_decl, ret, context, module, builder, &mut val_context, nv, opts, generics);
+        // the innermost loop is closed first, the exit of a loop continues the one around it
+        for (latch, exit) in loops.into_iter().rev() {
+            unsafe {
+                if LLVMGetBasicBlockTerminator(LLVMGetInsertBlock(builder)).is_null() {
+                    LLVMBuildBr(builder, latch);
+                }
+                LLVMPositionBuilderAtEnd(builder, exit);
+            }
+        }
+        unsafe {
+            // only unit functions can fall off their end, the others were checked to return on every path
+            if LLVMGetBasicBlockTerminator(LLVMGetInsertBlock(builder)).is_null() {
+                LLVMBuildRetVoid(builder);
             }
         }
-        val_context.push(base_var);
-        build_trivial_body(body.into_iter().map(|v| v.0).collect(), module_decl, ret, context, module, builder, &mut val_context);
         unsafe { LLVMVerifyFunction(func_obj, LLVMAbortProcessAction); };
         return func_obj;
     } else {
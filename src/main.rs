@@ -3,6 +3,11 @@ extern crate llvm_sys as llvm;
 mod parser;
 mod llvm_gen;
 mod llvm_context;
+mod generics;
+mod intrinsics;
+mod flow;
+mod race;
+mod refine;
 mod pool;
 
 use llvm::core::*;
@@ -19,6 +24,7 @@ pub use crate::parser::*;
 pub use crate::llvm_gen::*;
 pub use crate::llvm_context::*;
 pub use crate::pool::StringPool;
+use crate::generics::{Generics, substitute, substitute_decl};
 use llvm::transforms::scalar::{LLVMAddGVNPass, LLVMAddCFGSimplificationPass, LLVMAddReassociatePass};
 use std::collections::HashMap;
 use llvm::prelude::{LLVMValueRef, LLVMModuleRef};
@@ -28,10 +34,11 @@ use llvm::link_time_optimizer::llvm_create_optimizer;
 use std::ptr::null_mut;
 use std::os::raw::c_char;
 use std::collections::HashSet;
+use std::cell::Cell;
 use llvm::linker::LLVMLinkModules2;
 use llvm::analysis::LLVMVerifyModule;
 use llvm::analysis::LLVMVerifierFailureAction::LLVMAbortProcessAction;
-use llvm::transforms::ipo::LLVMAddFunctionInliningPass;
+use llvm::transforms::ipo::{LLVMAddFunctionInliningPass, LLVMAddGlobalDCEPass};
 use docopt::*;
 use serde_derive::Deserialize;
 use std::process::exit;
@@ -43,8 +50,12 @@ Usage:
   ruda [options] <filename>...
 
 Options:
-  -h --help     Show this screen
-  -o <file>     Place the output into <file>
+  -h --help         Show this screen
+  -o <file>         Place the output into <file>
+  --bounds-check    Trap on array accesses that cannot be proven in range
+  --libdevice <bc>  Link the libdevice bitcode the math functions of math.ru call
+  --target <gpu>    Emit IR for nvptx or amdgcn [default: nvptx]
+  --mcpu <cpu>      GPU the IR is meant for, e.g. sm_70 or gfx900, f16 arithmetic is native only when it has half instructions
 ";
 
 
@@ -52,19 +63,55 @@ Options:
 struct Args {
     arg_filename: Vec<String>,
     flag_o: Option<String>,
+    flag_bounds_check: bool,
+    flag_libdevice: Option<String>,
+    flag_target: String,
+    flag_mcpu: Option<String>,
 }
 
 fn main() {
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
-    do_compile(args.arg_filename, args.flag_o.unwrap_or("./a.ll".to_string()));
+    let target = Target::from_name(&args.flag_target[..]).unwrap_or_else(|| {
+        eprintln!("Unknown target : {}, expected nvptx or amdgcn", args.flag_target);
+        exit(1)
+    });
+    if target == Target::Amdgcn && args.flag_libdevice.is_some() {
+        eprintln!("libdevice is NVIDIA bitcode, it cannot be linked for amdgcn");
+        exit(1)
+    }
+    let options = CodegenOptions {
+        bounds_check: args.flag_bounds_check,
+        native_half: args.flag_mcpu.map(|cpu| target.has_half_arith(&cpu[..])).unwrap_or(false),
+        proven: HashSet::new(),
+        block_threads: Cell::new(MAX_BLOCK_THREADS),
+    };
+    do_compile(args.arg_filename, args.flag_o.unwrap_or("./a.ll".to_string()), options, args.flag_libdevice, target);
+}
+
+// Checks run on every function body before anything is declared, unreachable statements are dropped here
+fn check_body(func: TypedExpr, consts: &[(String, TyName)], options: &mut CodegenOptions, errors: &mut Vec<String>) -> TypedExpr {
+    let func = match flow::check_flow(func.clone()) {
+        Ok(func) => func,
+        Err(e) => {
+            errors.push(e);
+            func
+        }
+    };
+    race::check_races(&func.0);
+    errors.extend(intrinsics::check_stores(&func.0, consts));
+    errors.extend(refine::check_bounds(&func.0, options.bounds_check, &mut options.proven));
+    func
 }
 
-fn do_compile(files: Vec<String>, output: String) {
+fn do_compile(files: Vec<String>, output: String, mut options: CodegenOptions, libdevice: Option<String>, target: Target) {
     //if args().len() < 2 { panic!("ruda - no input file") };
     //let obj = args().last().unwrap().as_str();
-    let mut internal_module = String::from("; ModuleID = 'canoe_kernel'\n\n");
+    let mut internal_module = format!("; ModuleID = 'canoe_kernel'\ntarget datalayout = \"{}\"\ntarget triple = \"{}\"\n\n", target.data_layout(), target.triple());
+    if target == Target::Amdgcn {
+        internal_module.add_assign(AMDGCN_SUPPORT_IR);
+    }
     let mut file_list = files.iter().map(|f| std::path::PathBuf::from(&f[..]));
     let mut dedup : HashSet<std::path::PathBuf> = HashSet::new();
     let mut queue : Vec<std::path::PathBuf> = file_list.collect();
@@ -86,6 +133,7 @@ fn do_compile(files: Vec<String>, output: String) {
             println!("Cannot open file : {}", file.display());
             exit(1)
         }).read_to_string(&mut str).unwrap();
+        let file_name = file.display().to_string();
         dedup.insert(file);
         let syntax = RudaParser::parse(Rule::file
                 , source_pool.insert(str))
@@ -100,13 +148,13 @@ fn do_compile(files: Vec<String>, output: String) {
                 queue.push(path);
             }
         }
-        parsed_files.push(syntax);
+        parsed_files.push((file_name, syntax));
     }
     unsafe {
 // Set up a context, module and builder in that context.
         let context = Context::new();
 
-        let module = context.create_module("canoe".to_string());
+        let module = context.create_module("canoe".to_string(), target);
         let manager = context.function_pass_manager(module);
         let global_manager = context.global_pass_manager();
         //LLVMAddGlobalOptimizerPass(manager);
@@ -115,18 +163,78 @@ fn do_compile(files: Vec<String>, output: String) {
         LLVMAddReassociatePass(manager);
         LLVMInitializeFunctionPassManager(manager);
         LLVMAddFunctionInliningPass(global_manager);
+        LLVMAddGlobalDCEPass(global_manager);
 //        dbg!(&parser);
         let mut module_vals = HashMap::<String, Vec<(LLVMValueRef, TyName)>>::new();
         let mut func_pairs: Vec<(TypedExpr, LLVMValueRef)> = vec![];
-        let intrinsics = context.init_nvptx_intrinsics(module);
-        for func in parsed_files.into_iter().map(|parser| walk_pairs(parser)).flatten()
-                .filter(|f| if let BaseExpr::Nope = f.0 { false } else { true }) {
+        let mut generics = Generics::new();
+        let mut instances = vec![];
+        let intrinsics = context.init_intrinsics(module, target);
+        let items: Vec<TypedExpr> = parsed_files.into_iter().map(|(file, parser)| walk_pairs(parser, &file[..])).flatten()
+                .filter(|f| if let BaseExpr::Nope = f.0 { false } else { true }).collect();
+        // named types are resolved everywhere before declaring anything, records may refer to one another
+        let mut types = HashMap::<String, TyName>::new();
+        for item in items.iter() {
+            if let BaseExpr::TypeDecl(name, ty) = &item.0 {
+                types.insert(name.clone(), ty.clone());
+            }
+        }
+        for _ in 0..types.len() {
+            types = types.iter().map(|(name, ty)| (name.clone(), substitute(ty, &types))).collect();
+        }
+        // bodies may write to constants declared after them, which is rejected as well
+        let consts: Vec<_> = items.iter().filter_map(|item| match &item.0 {
+            BaseExpr::ConstDecl(name, ty) => Some((name.clone(), ty.clone())),
+            _ => None
+        }).collect();
+        let mut funcs = vec![];
+        let mut errors = vec![];
+        for item in items.into_iter().map(|item| substitute_decl(item, &types)) {
+            match item.0 {
+                BaseExpr::Impl(interface, ty, impl_funcs) => {
+                    let names = impl_funcs.iter().filter_map(|f| match &f.0 {
+                        BaseExpr::FuncDecl { ident, .. } | BaseExpr::IntrinsicsFuncDecl(ident, ..) | BaseExpr::FuncVirtualDecl(ident, ..) => Some(ident.clone()),
+                        _ => None
+                    }).collect();
+                    generics.register_impl(interface, ty, names);
+                    funcs.extend(impl_funcs.into_iter().map(|f| check_body(f, &consts, &mut options, &mut errors)));
+                }
+                BaseExpr::Interface(name, methods) => generics.register_interface(name, methods),
+                BaseExpr::TypeDecl(name, ty) => {
+                    if let TyName::Record(..) = ty {
+                        let ctor = llvm_record_ctor(&ty, context.context, module, context.builder);
+                        module_vals.entry(name).or_insert(vec![]).push(ctor);
+                    }
+                }
+                BaseExpr::ConstDecl(name, ty) => {
+                    let global = llvm_const_decl(&name[..], &ty, context.context, module);
+                    module_vals.entry(name).or_insert(vec![]).push(global);
+                }
+                BaseExpr::GenericFuncDecl(type_params, decl) => generics.register(type_params, check_body(*decl, &consts, &mut options, &mut errors).0),
+                BaseExpr::Instance(name, generic, type_args) => instances.push((name, generic, type_args)),
+                _ => funcs.push(check_body(item, &consts, &mut options, &mut errors))
+            }
+        }
+        if !errors.is_empty() {
+            for err in errors.iter() {
+                eprintln!("{}", err);
+            }
+            panic!("Function bodies failed to check, see the errors above")
+        }
+        for func in funcs.into_iter() {
             //dbg!(&func);
             let func_ref = llvm_declare_func(func.0.clone(), context.context, module, context.builder);
             let defs = module_vals.entry(func_ref.1).or_insert(vec![]);
             defs.push((func_ref.0, func.1.clone()));
             func_pairs.push((func, func_ref.0));
         }
+        for (name, generic, type_args) in instances.into_iter() {
+            let instance = generics.instantiate(&generic[..], &type_args, Some(name.clone()), context.context, module, context.builder);
+            module_vals.entry(name).or_insert(vec![]).push(instance);
+        }
+        generics.check_impls(&module_vals);
+        generics.check(&module_vals);
+        let mut declared = HashSet::new();
         for func_pair in func_pairs.into_iter() {
             let ident = Context::get_value_name(func_pair.1);
             let renamed_ident = ident.replace(".", "_"); // nvvm do not allow names with `.`, so we just replace it
@@ -134,6 +242,11 @@ fn do_compile(files: Vec<String>, output: String) {
             LLVMSetValueName(func_pair.1, string.as_ptr() as *const _);
             if let BaseExpr::IntrinsicsFuncDecl(_, params, ret, body) = (func_pair.0).0 {
                 let ident = Context::get_value_name(func_pair.1);
+                for (callee, decl) in llvm_embedded_decls(&body[..]) {
+                    if declared.insert(callee) {
+                        internal_module.add_assign(&decl[..]);
+                    }
+                }
                 let func_def = llvm_embedded_ir(ident, params, ret, body, context.context);
                 internal_module.add_assign(&func_def[..]);
                 internal_module.add_assign("\n\n");
@@ -145,9 +258,17 @@ fn do_compile(files: Vec<String>, output: String) {
                 internal_module.add_assign(&func_def[..]);
                 continue;
             }
-            llvm_define_func(func_pair.0, func_pair.1, &module_vals, context.context, module, context.builder, &intrinsics);
+            llvm_define_func(func_pair.0, func_pair.1, &module_vals, context.context, module, context.builder, &intrinsics, &options, &generics);
             LLVMRunFunctionPassManager(manager, func_pair.1);
         }
+        // instances are declared as calls to generic functions are generated, and may in turn instantiate more
+        while let Some((decl, func_ref)) = generics.pop_pending() {
+            let renamed_ident = Context::get_value_name(func_ref).replace(".", "_");
+            let string = CString::new(renamed_ident).unwrap();
+            LLVMSetValueName(func_ref, string.as_ptr() as *const _);
+            llvm_define_func(decl, func_ref, &module_vals, context.context, module, context.builder, &intrinsics, &options, &generics);
+            LLVMRunFunctionPassManager(manager, func_ref);
+        }
         let mut kernel_module: LLVMModuleRef = null_mut();
         let str_len = internal_module.len();
         let string = CString::new(internal_module).unwrap();
@@ -161,6 +282,7 @@ fn do_compile(files: Vec<String>, output: String) {
             panic!("Failed to compile kernel module, report for detailed reasons")
         }
         LLVMLinkModules2(module, kernel_module);
+        llvm_link_libdevice(module, libdevice, context.context);
         LLVMRunPassManager(global_manager, module);
         LLVMDisposeBuilder(context.builder);
         LLVMVerifyModule(module, LLVMAbortProcessAction, null_mut());
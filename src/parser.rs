@@ -1,5 +1,6 @@
 use std::iter::Extend;
 use std::collections::HashMap;
+use std::fmt;
 use pest::iterators::Pair;
 
 #[derive(Parser)]
@@ -14,11 +15,19 @@ pub enum TyName {
     VarBind(String),
     MutBind(Box<Self>),
     Array(Box<Self>),
+    Dense(Box<Self>, Vec<String>, Layout),
+    Record(String, Vec<(String, Self)>),
     Arrow(Box<Self>, Box<Self>),
     Tuple(Vec<Self>),
     Unit,
 }
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Layout {
+    RowMajor,
+    ColMajor,
+}
+
 impl TyName {
     fn get_arrow(params: Vec<TyName>, ret: TyName) -> TyName {
         TyName::Arrow(Box::new(TyName::Tuple(params)), Box::new(ret))
@@ -27,25 +36,62 @@ impl TyName {
 
 pub type TypedExpr = (BaseExpr, TyName);
 
+// A type parameter and the types it may be bound to, an empty bound accepts any type
+pub type TypeParam = (String, Vec<TyName>);
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct SrcLoc {
+    pub file: String,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl SrcLoc {
+    fn of(pair: &Pair<Rule>, file: &str) -> Self {
+        let (line, col) = pair.as_span().start_pos().line_col();
+        SrcLoc { file: file.to_string(), line, col }
+    }
+}
+
+impl fmt::Display for SrcLoc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum BaseExpr {
     IntrinsicsFuncDecl(String, Vec<(String, TyName)>, TyName, String),
     FuncVirtualDecl(String, Vec<(String, TyName)>, TyName),
-    FuncDecl { ident: String, para_in: Vec<String>, is_par: bool, params: Vec<(String, TyName)>, ret: TyName, body: Vec<TypedExpr> },
-    FuncCall(String, Vec<TypedExpr>),
+    // `ranges` holds the `n` of each `parfun<x in n>` index looping over `0..n`,
+    // `launch` holds the launch bounds of a kernel as written, e.g. `max_threads(256)`
+    FuncDecl { ident: String, para_in: Vec<String>, ranges: Vec<Option<TypedExpr>>, is_par: bool, launch: Vec<(String, Vec<i64>)>, params: Vec<(String, TyName)>, ret: TyName, body: Vec<TypedExpr> },
+    GenericFuncDecl(Vec<TypeParam>, Box<TypedExpr>),
+    Instance(String, String, Vec<TyName>),
+    TypeDecl(String, TyName),
+    // an array in constant memory, written by the host before launching and read-only on the device
+    ConstDecl(String, TyName),
+    Interface(String, Vec<(String, Vec<(String, TyName)>, TyName)>),
+    Impl(String, TyName, Vec<TypedExpr>),
+    FuncCall(String, Vec<TypedExpr>, SrcLoc),
+    // `reduce<i in n>(value, op, out, k..)` combines `value` over `0..n` with `op`, every thread of a block
+    // gets the total of its block, blocks combine theirs into `out[k..]` when it is given
+    Reduce { index: String, range: Box<TypedExpr>, value: Box<TypedExpr>, op: String, target: Vec<TypedExpr>, loc: SrcLoc },
     LetDecl(String, bool, Box<TypedExpr>),
+    SharedDecl(String, TyName),
     Assign(String, Box<TypedExpr>),
     IfExpr(Box<TypedExpr>, Vec<TypedExpr>, Option<Box<TypedExpr>>),
     Else(Option<Box<TypedExpr>>, Vec<TypedExpr>, Option<Box<TypedExpr>>),
     Return(Box<TypedExpr>),
     RetNull,
     Ident(String),
+    Member(Box<TypedExpr>, String),
     ConstantFloat(f64),
     ConstantInt(i64),
     Nope,
 }
 
-fn gen_bin_op(op: &String, lhs: TypedExpr, rhs: TypedExpr) -> TypedExpr {
+fn gen_bin_op(op: &String, lhs: TypedExpr, rhs: TypedExpr, loc: SrcLoc) -> TypedExpr {
     let name = match &op[..] {
         "+" => "add",
         "-" => "subtract",
@@ -59,12 +105,12 @@ fn gen_bin_op(op: &String, lhs: TypedExpr, rhs: TypedExpr) -> TypedExpr {
         "<=" => "lt_eq",
         _ => "nop"
     };
-    (BaseExpr::FuncCall(name.to_string(), vec![lhs, rhs]),
+    (BaseExpr::FuncCall(name.to_string(), vec![lhs, rhs], loc),
      TyName::VarBind(format!("ret@{}", name)))
 }
 
-pub fn walk_pairs(pairs: pest::iterators::Pairs<Rule>) -> Vec<TypedExpr> {
-    pairs.into_iter().map(walk_func).collect()
+pub fn walk_pairs(pairs: pest::iterators::Pairs<Rule>, file: &str) -> Vec<TypedExpr> {
+    pairs.into_iter().map(|func| walk_func(func, file)).collect()
 }
 
 macro_rules! parse_param {
@@ -89,13 +135,73 @@ fn collect_decl(ret_type: TyName, decl: RuleList) -> TypedExpr {
      , TyName::get_arrow(params.into_iter().map(|v| v.1).collect(), ret_type))
 }
 
-fn walk_func(func: Pair<Rule>) -> TypedExpr {
+fn walk_type_params(params: Pair<Rule>) -> Vec<TypeParam> {
+    params.into_inner().map(|param| {
+        let pair: RuleList = param.into_inner().collect();
+        let bound = pair.get(1).map(|b| b.clone().into_inner().map(walk_ty).collect()).unwrap_or(vec![]);
+        (pair[0].as_str().to_string(), bound)
+    }).collect()
+}
+
+fn walk_func(func: Pair<Rule>, file: &str) -> TypedExpr {
     dbg!(&func);
     if func.as_rule() == Rule::import_module {
         return (BaseExpr::Nope, TyName::Unit)
     }
+    if func.as_rule() == Rule::type_decl {
+        let composition: RuleList = func.into_inner().collect();
+        let name = composition[0].as_str().to_string();
+        let ty = match walk_ty(composition[1].clone()) {
+            TyName::Record(_, fields) => TyName::Record(name.clone(), fields),
+            ty => ty
+        };
+        return (BaseExpr::TypeDecl(name, ty), TyName::Unit)
+    }
+    if func.as_rule() == Rule::const_decl {
+        let composition: RuleList = func.into_inner().collect();
+        return (BaseExpr::ConstDecl(composition[0].as_str().to_string(), walk_ty(composition[1].clone())), TyName::Unit)
+    }
+    if func.as_rule() == Rule::interface {
+        let composition: RuleList = func.into_inner().collect();
+        let methods = composition[1..].iter().map(|method| {
+            let decl: RuleList = method.clone().into_inner().filter(|p| p.as_rule() != Rule::type_params).collect();
+            if decl[0].as_rule() == Rule::parfun_decl {
+                panic!("Interface `{}` cannot declare kernels", composition[0].as_str());
+            }
+            let ret = decl.get(2).map(|r| walk_ty(r.clone())).unwrap_or(TyName::Unit);
+            (decl[0].as_str().to_string(), parse_param!(decl[1].clone().into_inner()), ret)
+        }).collect();
+        return (BaseExpr::Interface(composition[0].as_str().to_string(), methods), TyName::Unit)
+    }
+    if func.as_rule() == Rule::impl_block {
+        let composition: RuleList = func.into_inner().collect();
+        let funcs = composition[2..].iter().map(|f| walk_func(f.clone(), file)).collect();
+        return (BaseExpr::Impl(composition[0].as_str().to_string(), walk_ty(composition[1].clone()), funcs), TyName::Unit)
+    }
+    if func.as_rule() == Rule::instance {
+        let composition: RuleList = func.into_inner().collect();
+        let args = composition[2].clone().into_inner().map(walk_ty).collect();
+        return (BaseExpr::Instance(composition[0].as_str().to_string(), composition[1].as_str().to_string(), args), TyName::Unit)
+    }
     let vec: RuleList = func.into_inner().collect();
     let decl: RuleList = vec[0].clone().into_inner().collect();
+    let type_params = decl.iter().find(|p| p.as_rule() == Rule::type_params).map(|p| walk_type_params(p.clone()));
+    let decl: RuleList = decl.into_iter().filter(|p| p.as_rule() != Rule::type_params).collect();
+    let func = walk_func_decl(decl, vec, file);
+    match type_params {
+        Some(type_params) => {
+            if let BaseExpr::FuncDecl { .. } = func.0 {
+                let ty = func.1.clone();
+                (BaseExpr::GenericFuncDecl(type_params, Box::new(func)), ty)
+            } else {
+                panic!("Only functions with a body can take type parameters")
+            }
+        }
+        None => func
+    }
+}
+
+fn walk_func_decl(decl: RuleList, vec: RuleList, file: &str) -> TypedExpr {
     let ret_type = if decl.last().unwrap().as_rule() == Rule::ret_type {
         walk_ty(decl.last().unwrap().clone())
     } else {
@@ -110,16 +216,31 @@ fn walk_func(func: Pair<Rule>) -> TypedExpr {
     let body: RuleList = vec[1].clone().into_inner().collect();
     let mut para_in: Vec<String> = vec![];
     if decl[0].as_rule() == Rule::parfun_decl {
-        para_in = decl[0].clone().into_inner().next().unwrap().clone().into_inner().map(|i| i.as_span().as_str().to_string()).collect();
+        let parfun: RuleList = decl[0].clone().into_inner().collect();
+        let (names, ranges): (Vec<_>, Vec<_>) = parfun[0].clone().into_inner().map(|binding| {
+            let composition: RuleList = binding.into_inner().collect();
+            let range = composition.get(1).map(|range| match range.as_rule() {
+                Rule::func_call => walk_func_call(range.clone(), file),
+                _ => walk_value_node(range.clone().into_inner().collect())
+            });
+            (composition[0].as_span().as_str().to_string(), range)
+        }).unzip();
+        para_in = names;
+        let launch = parfun.get(1).map(|attrs| attrs.clone().into_inner().map(|attr| {
+            let composition: RuleList = attr.into_inner().collect();
+            (composition[0].as_str().to_string(), composition[1..].iter().map(|n| n.as_str().parse().unwrap()).collect())
+        }).collect()).unwrap_or(vec![]);
         let name = decl[1].as_span().as_str().to_string();
         let params: Vec<_> = parse_param!(decl[2].clone().into_inner());
         return (BaseExpr::FuncDecl {
             ident: name,
             para_in,
+            ranges,
             params: params.clone(),
             ret: ret_type.clone(),
             is_par: true,
-            body: walk_fun_body(body),
+            launch,
+            body: walk_fun_body(body, file),
         }, TyName::get_arrow(params.into_iter().map(|v| v.1).collect(), ret_type));
     } else {
         let name = decl[0].as_span().as_str().to_string();
@@ -127,17 +248,19 @@ fn walk_func(func: Pair<Rule>) -> TypedExpr {
         return (BaseExpr::FuncDecl {
             ident: name,
             para_in,
+            ranges: vec![],
             params: params.clone(),
             ret: ret_type.clone(),
             is_par: false,
-            body: walk_fun_body(body),
+            launch: vec![],
+            body: walk_fun_body(body, file),
         }, TyName::get_arrow(params.into_iter().map(|v| v.1).collect(), ret_type));
     }
 }
 
-fn walk_fun_body(body: RuleList) -> Vec<TypedExpr> {
+fn walk_fun_body(body: RuleList, file: &str) -> Vec<TypedExpr> {
     body.into_iter().map(|expr| {
-        if expr.as_rule() == Rule::base_expr {
+        if expr.as_rule() == Rule::base_expr || expr.as_rule() == Rule::block_expr {
             let inner = expr.into_inner().take(1).collect::<RuleList>()[0].clone();
             return match inner.as_rule() {
                 Rule::return_expr => {
@@ -146,10 +269,15 @@ fn walk_fun_body(body: RuleList) -> Vec<TypedExpr> {
                         (BaseExpr::RetNull, TyName::Unit)
                     } else {
                         let value_expr = vec[0].clone();
-                        let walked_val = walk_value_expr(value_expr.into_inner().collect());
+                        let walked_val = walk_value_expr(value_expr.into_inner().collect(), file);
                         (BaseExpr::Return(Box::new(walked_val.clone())), walked_val.1)
                     }
                 }
+                Rule::shared_decl => {
+                    let composition = inner.into_inner().collect::<RuleList>();
+                    let id = composition[0].as_str().to_string();
+                    (BaseExpr::SharedDecl(id, walk_ty(composition[1].clone())), TyName::Unit)
+                }
                 Rule::let_expr => {
                     let composition = inner.into_inner().collect::<RuleList>();
                     let mut base = 0;
@@ -157,20 +285,20 @@ fn walk_fun_body(body: RuleList) -> Vec<TypedExpr> {
                         base = 1;
                     }
                     let id = composition[base].as_str().to_string();
-                    let val = walk_value_expr(composition[base + 1].clone().into_inner().collect());
+                    let val = walk_value_expr(composition[base + 1].clone().into_inner().collect(), file);
                     (BaseExpr::LetDecl(id, base > 0, Box::new(val)), TyName::Unit)
                 }
                 Rule::assignment => {
                     let composition = inner.into_inner().collect::<RuleList>();
                     let id = composition[0].as_str().to_string();
-                    let val = walk_value_expr(composition[1].clone().into_inner().collect());
+                    let val = walk_value_expr(composition[1].clone().into_inner().collect(), file);
                     (BaseExpr::Assign(id, Box::new(val)), TyName::Unit)
                 }
                 Rule::value_expr => {
-                    walk_value_expr(inner.into_inner().collect())
+                    walk_value_expr(inner.into_inner().collect(), file)
                 }
                 Rule::if_expr => {
-                    walk_if_cond_branch(inner.into_inner().collect())
+                    walk_if_cond_branch(inner.into_inner().collect(), file)
                 }
                 Rule::while_expr => {
                     let composition = inner.into_inner().collect::<RuleList>();
@@ -188,11 +316,24 @@ fn walk_fun_body(body: RuleList) -> Vec<TypedExpr> {
     ).collect()
 }
 
-fn walk_if_cond_branch(body: RuleList) -> TypedExpr {
-    (BaseExpr::Nope, TyName::Unit)
+// `elif` and `else` branches are chained as `Else(condition, body, next)`, a final `else` has no condition
+fn walk_if_cond_branch(body: RuleList, file: &str) -> TypedExpr {
+    let cond = walk_value_expr(body[0].clone().into_inner().collect(), file);
+    let then_body = walk_fun_body(body[1].clone().into_inner().collect(), file);
+    let next = body[2..].iter().rev().fold(None, |next, branch| {
+        let inner: RuleList = branch.clone().into_inner().collect();
+        let walked = if branch.as_rule() == Rule::if_else_expr {
+            let cond = walk_value_expr(inner[0].clone().into_inner().collect(), file);
+            BaseExpr::Else(Some(Box::new(cond)), walk_fun_body(inner[1].clone().into_inner().collect(), file), next)
+        } else {
+            BaseExpr::Else(None, walk_fun_body(inner[0].clone().into_inner().collect(), file), None)
+        };
+        Some(Box::new((walked, TyName::Unit)))
+    });
+    (BaseExpr::IfExpr(Box::new(cond), then_body, next), TyName::Unit)
 }
 
-fn walk_value_expr(body: RuleList) -> TypedExpr {
+fn walk_value_expr(body: RuleList, file: &str) -> TypedExpr {
     let mut priority = HashMap::<&str, (i32, bool)>::new();
     priority.insert("<", (5, true));
     priority.insert("<=", (5, true));
@@ -204,25 +345,27 @@ fn walk_value_expr(body: RuleList) -> TypedExpr {
     priority.insert("-", (10, true));
     priority.insert("*", (20, true));
     priority.insert("/", (20, true));
-    walk_value_expr_with_climber(body, 0, &priority).0
+    walk_value_expr_with_climber(body, 0, &priority, file).0
 }
 
-fn walk_value_expr_with_climber<'a>(body: RuleList<'a>, prec_val: i32, priority: &HashMap<&str, (i32, bool)>) -> (TypedExpr, Option<RuleList<'a>>) {
+fn walk_value_expr_with_climber<'a>(body: RuleList<'a>, prec_val: i32, priority: &HashMap<&str, (i32, bool)>, file: &str) -> (TypedExpr, Option<RuleList<'a>>) {
     let primary = body[0].clone();
     match primary.as_rule() {
         Rule::value => {
             (walk_value_node(primary.into_inner().collect()), None)
         }
         Rule::func_call => {
-            let composition = primary.into_inner().collect::<RuleList>();
-            let id = composition[0].as_str().to_string();
-            ((BaseExpr::FuncCall(id.clone(), composition.into_iter().skip(1)
-                .map(|v| walk_value_expr(v.into_inner().collect())).collect())
-              , TyName::VarBind(format!("ret@{}", id))), None)
+            (walk_func_call(primary, file), None)
+        }
+        Rule::reduce_expr => {
+            (walk_reduce(primary, file), None)
         }
         Rule::bin_op => { // prec climber
             let composition = primary.into_inner().collect::<RuleList>();
-            let lhs = walk_value_node(composition[0].clone().into_inner().collect());
+            let lhs = match composition[0].as_rule() {
+                Rule::func_call => walk_func_call(composition[0].clone(), file),
+                _ => walk_value_node(composition[0].clone().into_inner().collect())
+            };
             let mut last_op = composition[1].clone();
             let mut op = last_op.as_str().to_string();
             let mut prior = priority.get(&op[..]).expect("Operator not found !");
@@ -231,9 +374,9 @@ fn walk_value_expr_with_climber<'a>(body: RuleList<'a>, prec_val: i32, priority:
             while prior.0 >= prec_val {
                 //println!("{} {} {:?}", prec_val, op, prior);
                 let next_prior = if prior.1 { prior.0 + 1 } else { prior.0 };
-                let rhs = walk_value_expr_with_climber(remnants.clone(), next_prior, priority);
+                let rhs = walk_value_expr_with_climber(remnants.clone(), next_prior, priority, file);
                 let suc = rhs.1;
-                result = gen_bin_op(&op, result, rhs.0);
+                result = gen_bin_op(&op, result, rhs.0, SrcLoc::of(&last_op, file));
                 match suc {
                     None => {
                         return (result, None);
@@ -261,10 +404,41 @@ fn walk_value_expr_with_climber<'a>(body: RuleList<'a>, prec_val: i32, priority:
     }
 }
 
+fn walk_func_call(call: Pair<Rule>, file: &str) -> TypedExpr {
+    let loc = SrcLoc::of(&call, file);
+    let composition = call.into_inner().collect::<RuleList>();
+    let id = composition[0].as_str().to_string();
+    (BaseExpr::FuncCall(id.clone(), composition.into_iter().skip(1)
+        .map(|v| walk_value_expr(v.into_inner().collect(), file)).collect(), loc)
+     , TyName::VarBind(format!("ret@{}", id)))
+}
+
+fn walk_reduce(reduce: Pair<Rule>, file: &str) -> TypedExpr {
+    let loc = SrcLoc::of(&reduce, file);
+    let composition = reduce.into_inner().collect::<RuleList>();
+    let range = match composition[1].as_rule() {
+        Rule::func_call => walk_func_call(composition[1].clone(), file),
+        _ => walk_value_node(composition[1].clone().into_inner().collect())
+    };
+    let op = composition[3].as_str().to_string();
+    (BaseExpr::Reduce {
+        index: composition[0].as_str().to_string(),
+        range: Box::new(range),
+        value: Box::new(walk_value_expr(composition[2].clone().into_inner().collect(), file)),
+        op: op.clone(),
+        target: composition[4..].iter().map(|v| walk_value_expr(v.clone().into_inner().collect(), file)).collect(),
+        loc,
+    }, TyName::VarBind(format!("reduce@{}", op)))
+}
+
 fn walk_value_node(body: RuleList) -> TypedExpr {
     let inner = body[0].clone();
     if inner.as_rule() == Rule::ident {
-        return (BaseExpr::Ident(inner.as_str().to_string()), TyName::VarBind(inner.as_str().to_string()));
+        // `v.xy` is lexed as a single ident, split it into member accesses
+        let mut path = inner.as_str().split('.');
+        let base = path.next().unwrap().to_string();
+        return path.fold((BaseExpr::Ident(base.clone()), TyName::VarBind(base)), |obj, field|
+            (BaseExpr::Member(Box::new(obj), field.to_string()), TyName::VarBind(format!("member@{}", field))));
     } else if inner.as_rule() == Rule::number {
         return (BaseExpr::ConstantInt(inner.as_str().to_string().parse().unwrap()), TyName::NameBind(String::from("i64")));
     }
@@ -277,7 +451,28 @@ fn walk_ty(ty: Pair<Rule>) -> TyName {
     } else if ty.as_rule() == Rule::mut_type {
         return TyName::MutBind(Box::new(walk_ty(ty.into_inner().next().unwrap())));
     } else if ty.as_rule() == Rule::arr_type {
-        return TyName::Array(Box::new(walk_ty(ty.into_inner().next().unwrap())));
+        let composition: RuleList = ty.into_inner().collect();
+        let elem = Box::new(walk_ty(composition[0].clone()));
+        if composition.len() == 1 {
+            return TyName::Array(elem);
+        }
+        let mut extents = vec![];
+        let mut layout = Layout::RowMajor;
+        for item in composition[1].clone().into_inner() {
+            if item.as_rule() == Rule::layout {
+                layout = if item.as_str().eq("col_major") { Layout::ColMajor } else { Layout::RowMajor };
+            } else {
+                extents.push(item.as_str().to_string());
+            }
+        }
+        return TyName::Dense(elem, extents, layout);
+    } else if ty.as_rule() == Rule::typedef {
+        // records are anonymous until a `type` declaration names them
+        let fields = ty.into_inner().next().unwrap().into_inner().map(|record| {
+            let pair: RuleList = record.into_inner().collect();
+            (pair[0].as_str().to_string(), walk_ty(pair[1].clone()))
+        }).collect();
+        return TyName::Record(String::new(), fields);
     } else if ty.as_rule() == Rule::type_ident || ty.as_rule() == Rule::immut_type || ty.as_rule() == Rule::ret_type {
         return walk_ty(ty.into_inner().next().unwrap());
     } else {
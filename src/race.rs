@@ -0,0 +1,371 @@
+use crate::parser::*;
+use crate::intrinsics::access_indices;
+use std::collections::HashMap;
+
+/*
+Race checking for parfun bodies, after the component view of a kernel :
+every thread owns the elements it stores to at an index that is injective
+in all of its thread indices, anything else is shared between threads.
+    thread-private : injective index, every other access uses the same index
+    shared         : injective index, other threads' elements are accessed
+                     but only across a `@sync` barrier
+    race           : the index is not injective, or other threads' elements
+                     are accessed without a barrier in between
+Indices are classified without knowing values, so `y * w + x` is assumed to
+stay in range and is taken as injective in both `x` and `y`. Shared arrays
+only hold the elements of one block, `@thread_idx(d)` covers dimension `d` there.
+A `@sync` only returns once every thread of the block reached it, so one under a
+thread-dependent condition, or after a thread-dependent early return, deadlocks.
+`reduce` synchronizes the block the same way.
+Warp shuffles and votes likewise expect every lane of the warp.
+*/
+#[derive(Debug, Clone, PartialEq)]
+enum Index {
+    // the same for every thread
+    Uniform,
+    // injective in these thread indices
+    Affine(Vec<String>),
+    // depends on the thread in a way that may collide
+    Varying,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sharing {
+    ThreadPrivate,
+    SharedWithBarrier,
+    Race(String),
+}
+
+struct Access {
+    array: String,
+    indices: Vec<String>,
+    store: bool,
+    stmt: usize,
+    loc: SrcLoc,
+}
+
+struct Analysis {
+    // thread indices with their dimension
+    threads: Vec<(usize, String)>,
+    shared: Vec<String>,
+    lets: HashMap<String, BaseExpr>,
+    accesses: Vec<Access>,
+    barriers: Vec<usize>,
+}
+
+fn combine(lhs: Index, rhs: Index) -> Index {
+    match (lhs, rhs) {
+        (Index::Uniform, other) | (other, Index::Uniform) => other,
+        (Index::Affine(mut a), Index::Affine(b)) => {
+            if b.iter().any(|v| a.contains(v)) {
+                return Index::Varying;
+            }
+            a.extend(b);
+            Index::Affine(a)
+        }
+        _ => Index::Varying
+    }
+}
+
+// Inlines the `let` bindings in scope. Bindings are stored resolved against the ones before
+// them, so a resolved expression never has to be looked up again, even after `let i = i + 1`
+pub(crate) fn resolve_lets(expr: &BaseExpr, lets: &HashMap<String, BaseExpr>) -> BaseExpr {
+    match expr {
+        BaseExpr::Ident(id) => lets.get(id).cloned().unwrap_or_else(|| expr.clone()),
+        BaseExpr::Member(obj, field) => BaseExpr::Member(Box::new((resolve_lets(&obj.0, lets), obj.1.clone())), field.clone()),
+        BaseExpr::FuncCall(ident, args, loc) =>
+            BaseExpr::FuncCall(ident.clone(), args.iter().map(|a| (resolve_lets(&a.0, lets), a.1.clone())).collect(), loc.clone()),
+        _ => expr.clone()
+    }
+}
+
+// Index expressions are compared as text, with `let` bindings inlined
+pub(crate) fn show_index(expr: &BaseExpr, lets: &HashMap<String, BaseExpr>) -> String {
+    show_resolved(&resolve_lets(expr, lets))
+}
+
+pub(crate) fn show_resolved(expr: &BaseExpr) -> String {
+    match expr {
+        BaseExpr::ConstantInt(v) => v.to_string(),
+        BaseExpr::ConstantFloat(v) => v.to_string(),
+        BaseExpr::Ident(id) => id.clone(),
+        BaseExpr::Member(obj, field) => format!("{}.{}", show_resolved(&obj.0), field),
+        BaseExpr::FuncCall(ident, args, _) => {
+            let args: Vec<_> = args.iter().map(|a| show_resolved(&a.0)).collect();
+            let op = match &ident[..] { "add" => "+", "subtract" => "-", "multiply" => "*", "divide" => "/", _ => "" };
+            if !op.is_empty() && args.len() == 2 {
+                format!("({} {} {})", args[0], op, args[1])
+            } else {
+                format!("{}({})", ident, args.join(", "))
+            }
+        }
+        _ => String::from("_")
+    }
+}
+
+// Branches are flattened in order, so a barrier anywhere between two accesses separates them
+fn flatten(body: &Vec<TypedExpr>, out: &mut Vec<BaseExpr>) {
+    for expr in body.iter() {
+        match &expr.0 {
+            BaseExpr::IfExpr(cond, body, next) | BaseExpr::Else(Some(cond), body, next) => {
+                out.push(cond.0.clone());
+                flatten(body, out);
+                if let Some(next) = next {
+                    flatten(&vec![(**next).clone()], out);
+                }
+            }
+            BaseExpr::Else(None, body, _) => flatten(body, out),
+            other => out.push(other.clone())
+        }
+    }
+}
+
+impl Analysis {
+    fn classify(&self, expr: &BaseExpr) -> Index {
+        self.classify_resolved(&resolve_lets(expr, &self.lets))
+    }
+
+    fn classify_resolved(&self, expr: &BaseExpr) -> Index {
+        match expr {
+            BaseExpr::ConstantInt(_) | BaseExpr::ConstantFloat(_) => Index::Uniform,
+            BaseExpr::Ident(id) => if self.threads.iter().any(|t| t.1.eq(id)) { Index::Affine(vec![id.clone()]) } else { Index::Uniform },
+            BaseExpr::FuncCall(ident, _, _) if ident.eq("@thread_idx") => Index::Affine(vec![show_resolved(expr)]),
+            // atomics give every thread another previous value but nothing tells which, lanes repeat in every warp
+            BaseExpr::FuncCall(ident, _, _) if ident.starts_with("@atomic_") || ident.eq("@lane_id") => Index::Varying,
+            BaseExpr::FuncCall(ident, args, _) => {
+                let classes: Vec<_> = args.iter().map(|a| self.classify_resolved(&a.0)).collect();
+                match (&ident[..], &classes[..]) {
+                    ("add", [lhs, rhs]) | ("subtract", [lhs, rhs]) => combine(lhs.clone(), rhs.clone()),
+                    ("multiply", [Index::Affine(v), Index::Uniform]) | ("multiply", [Index::Uniform, Index::Affine(v)]) => {
+                        if args.iter().any(|a| if let BaseExpr::ConstantInt(0) = a.0 { true } else { false }) { Index::Uniform } else { Index::Affine(v.clone()) }
+                    }
+                    _ if classes.iter().all(|c| *c == Index::Uniform) => Index::Uniform,
+                    _ => Index::Varying
+                }
+            }
+            BaseExpr::Member(obj, _) => if self.classify_resolved(&obj.0) == Index::Uniform { Index::Uniform } else { Index::Varying },
+            _ => Index::Uniform
+        }
+    }
+
+    fn collect(&mut self, expr: &BaseExpr, stmt: usize) {
+        match expr {
+            BaseExpr::FuncCall(ident, args, loc) => {
+                for arg in args.iter() {
+                    self.collect(&arg.0, stmt);
+                }
+                if ident.eq("@sync") {
+                    self.barriers.push(stmt);
+                }
+                let indices = match access_indices(&ident[..], args) {
+                    Some(indices) => indices,
+                    None => return
+                };
+                if let Some((BaseExpr::Ident(array), _)) = args.first() {
+                    let access = Access {
+                        array: array.clone(),
+                        indices: indices.iter().map(|i| show_index(&i.0, &self.lets)).collect(),
+                        store: !ident.eq("@load"),
+                        stmt,
+                        loc: loc.clone(),
+                    };
+                    self.accesses.push(access);
+                }
+            }
+            BaseExpr::Reduce { range, value, target, .. } => {
+                self.collect(&range.0, stmt);
+                self.collect(&value.0, stmt);
+                for arg in target.iter() {
+                    self.collect(&arg.0, stmt);
+                }
+                self.barriers.push(stmt);
+            }
+            BaseExpr::SharedDecl(id, _) => self.shared.push(id.clone()),
+            BaseExpr::LetDecl(id, _, value) => {
+                self.collect(&value.0, stmt);
+                let value = resolve_lets(&value.0, &self.lets);
+                self.lets.insert(id.clone(), value);
+            }
+            BaseExpr::Return(value) | BaseExpr::Assign(_, value) => self.collect(&value.0, stmt),
+            BaseExpr::Member(obj, _) => self.collect(&obj.0, stmt),
+            _ => {}
+        }
+    }
+
+    fn injective(&self, array: &str, indices: &[TypedExpr]) -> Result<(), String> {
+        let mut covered: Vec<String> = vec![];
+        for index in indices.iter() {
+            match self.classify(&index.0) {
+                Index::Varying => return Err(format!("index `{}` may be equal for different threads", show_index(&index.0, &self.lets))),
+                Index::Affine(vars) => covered.extend(vars),
+                Index::Uniform => {}
+            }
+        }
+        let shared = self.shared.iter().any(|s| s.eq(array));
+        let uncovered = self.threads.iter().find(|(dim, t)| !covered.contains(t) && !(shared && covered.contains(&format!("@thread_idx({})", dim))));
+        match uncovered.map(|t| &t.1) {
+            Some(t) => Err(format!("the index does not depend on thread index `{}`, so threads differing only in `{}` store to the same element", t, t)),
+            None => Ok(())
+        }
+    }
+
+    fn barrier_between(&self, a: usize, b: usize) -> bool {
+        let (lo, hi) = if a < b { (a, b) } else { (b, a) };
+        self.barriers.iter().any(|s| lo < *s && *s < hi)
+    }
+}
+
+// Classifies every `@store` of a parfun body, in order of appearance
+pub fn classify_stores(para_in: &Vec<String>, body: &Vec<TypedExpr>) -> Vec<(SrcLoc, String, Sharing)> {
+    let mut analysis = Analysis {
+        threads: para_in.iter().cloned().enumerate().filter(|p| p.1.ne("_")).collect(),
+        shared: vec![],
+        lets: HashMap::new(),
+        accesses: vec![],
+        barriers: vec![],
+    };
+    let mut stores = vec![];
+    let mut statements = vec![];
+    flatten(body, &mut statements);
+    for (stmt, expr) in statements.iter().enumerate() {
+        if let BaseExpr::FuncCall(ident, args, loc) = expr {
+            if ident.eq("@store") && args.len() > 1 {
+                if let (BaseExpr::Ident(array), _) = &args[0] {
+                    let indices = access_indices(&ident[..], args).unwrap_or(&[]).to_vec();
+                    let private = analysis.injective(array, &indices);
+                    stores.push((stmt, array.clone(), loc.clone(), private));
+                }
+            }
+        }
+        analysis.collect(expr, stmt);
+    }
+    stores.into_iter().map(|(stmt, array, loc, private)| {
+        let sharing = match private {
+            Err(reason) => Sharing::Race(reason),
+            Ok(()) => {
+                let own = analysis.accesses.iter().find(|a| a.store && a.stmt == stmt && a.array.eq(&array)).map(|a| a.indices.clone()).unwrap_or(vec![]);
+                let others: Vec<_> = analysis.accesses.iter().filter(|a| a.array.eq(&array) && a.indices != own).collect();
+                match others.iter().find(|a| !analysis.barrier_between(a.stmt, stmt)) {
+                    Some(other) => Sharing::Race(format!("`{}` is {} at {} with index `{}` without a `@sync` in between",
+                                                         array, if other.store { "stored" } else { "loaded" }, other.loc, other.indices.join(", "))),
+                    None if others.is_empty() => Sharing::ThreadPrivate,
+                    None => Sharing::SharedWithBarrier,
+                }
+            }
+        };
+        (loc, array, sharing)
+    }).collect()
+}
+
+fn contains_return(body: &Vec<TypedExpr>) -> bool {
+    body.iter().any(|expr| match &expr.0 {
+        BaseExpr::Return(_) | BaseExpr::RetNull => true,
+        BaseExpr::IfExpr(_, body, next) | BaseExpr::Else(_, body, next) =>
+            contains_return(body) || next.as_ref().map(|n| contains_return(&vec![(**n).clone()])).unwrap_or(false),
+        _ => false
+    })
+}
+
+// Intrinsics that every lane of a warp has to execute together
+const WARP_COLLECTIVES: &[&str] = &["@shfl_up", "@shfl_down", "@shfl_xor", "@shfl_idx", "@vote_any", "@vote_all", "@ballot"];
+
+fn find_syncs(expr: &BaseExpr, found: &mut Vec<(SrcLoc, String)>) {
+    match expr {
+        BaseExpr::FuncCall(ident, args, loc) => {
+            if ident.eq("@sync") || WARP_COLLECTIVES.contains(&&ident[..]) {
+                found.push((loc.clone(), ident.clone()));
+            }
+            for arg in args.iter() {
+                find_syncs(&arg.0, found);
+            }
+        }
+        BaseExpr::Reduce { range, value, target, loc, .. } => {
+            found.push((loc.clone(), String::from("reduce")));
+            find_syncs(&range.0, found);
+            find_syncs(&value.0, found);
+            for arg in target.iter() {
+                find_syncs(&arg.0, found);
+            }
+        }
+        BaseExpr::LetDecl(_, _, value) | BaseExpr::Assign(_, value) | BaseExpr::Return(value) | BaseExpr::Member(value, _) => find_syncs(&value.0, found),
+        _ => {}
+    }
+}
+
+impl Analysis {
+    // Barriers have to be reached by every thread of a block, `guard` tells why some may not
+    fn divergent_syncs(&self, body: &Vec<TypedExpr>, mut guard: Option<String>, found: &mut Vec<(SrcLoc, String, String)>) {
+        for expr in body.iter() {
+            match &expr.0 {
+                BaseExpr::IfExpr(cond, body, next) | BaseExpr::Else(Some(cond), body, next) => {
+                    let mut syncs = vec![];
+                    find_syncs(&cond.0, &mut syncs);
+                    found.extend(syncs.into_iter().filter_map(|(loc, name)| guard.clone().map(|g| (loc, name, g))));
+                    let inner = guard.clone().or_else(|| if self.classify(&cond.0) != Index::Uniform {
+                        Some(format!("is under the thread-dependent condition `{}`", show_index(&cond.0, &self.lets)))
+                    } else {
+                        None
+                    });
+                    self.divergent_syncs(body, inner.clone(), found);
+                    if let Some(next) = next {
+                        self.divergent_syncs(&vec![(**next).clone()], inner.clone(), found);
+                    }
+                    // threads returning early never arrive at the barriers that follow
+                    if guard.is_none() && inner.is_some() && (contains_return(body) || next.as_ref().map(|n| contains_return(&vec![(**n).clone()])).unwrap_or(false)) {
+                        guard = Some(format!("follows a return under the thread-dependent condition `{}`", show_index(&cond.0, &self.lets)));
+                    }
+                }
+                BaseExpr::Else(None, body, _) => self.divergent_syncs(body, guard.clone(), found),
+                other => {
+                    let mut syncs = vec![];
+                    find_syncs(other, &mut syncs);
+                    found.extend(syncs.into_iter().filter_map(|(loc, name)| guard.clone().map(|g| (loc, name, g))));
+                }
+            }
+        }
+    }
+}
+
+// `@sync` and warp collective calls that some threads may never reach, with the reason why
+pub fn divergent_syncs(para_in: &Vec<String>, ranges: &Vec<Option<TypedExpr>>, body: &Vec<TypedExpr>) -> Vec<(SrcLoc, String, String)> {
+    let mut analysis = Analysis {
+        threads: para_in.iter().cloned().enumerate().filter(|p| p.1.ne("_")).collect(),
+        shared: vec![],
+        lets: HashMap::new(),
+        accesses: vec![],
+        barriers: vec![],
+    };
+    let mut statements = vec![];
+    flatten(body, &mut statements);
+    for (stmt, expr) in statements.iter().enumerate() {
+        analysis.collect(expr, stmt);
+    }
+    // threads run a grid-stride loop as many times as they have elements
+    let strided = para_in.iter().zip(ranges.iter()).find_map(|(thread, range)| range.as_ref()
+        .map(|r| format!("is inside the grid-stride loop over `{} in {}`", thread, show_index(&r.0, &analysis.lets))));
+    // the threads past the last index folded onto z skip the body
+    let strided = strided.or_else(|| para_in.last().filter(|_| para_in.len() > 3)
+        .map(|last| format!("is skipped by the threads past the extent of `{}`, the last index folded onto z", last)));
+    let mut found = vec![];
+    analysis.divergent_syncs(body, strided, &mut found);
+    found
+}
+
+// Likely races are reported as warnings, the analysis cannot rule out false positives
+pub fn check_races(func: &BaseExpr) {
+    if let BaseExpr::FuncDecl { ident, para_in, ranges, is_par: true, body, .. } = func {
+        for (loc, array, sharing) in classify_stores(para_in, body) {
+            if let Sharing::Race(reason) = sharing {
+                eprintln!("{}: warning: possible data race on `{}` in parfun `{}`, {}", loc, array, ident, reason);
+            }
+        }
+        for (loc, name, reason) in divergent_syncs(para_in, ranges, body) {
+            let effect = if name.eq("@sync") || name.eq("reduce") {
+                "threads that never reach it leave the rest of the block waiting forever"
+            } else {
+                "lanes that never reach it make its result undefined for the whole warp"
+            };
+            eprintln!("{}: warning: `{}` in parfun `{}` {}, {}", loc, name, ident, reason, effect);
+        }
+    }
+}
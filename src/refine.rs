@@ -0,0 +1,394 @@
+use crate::parser::*;
+use crate::llvm_gen::{shape_of, shape_kernel_arrays};
+use crate::race::{resolve_lets, show_resolved};
+use crate::intrinsics::access_indices;
+use std::collections::{HashMap, HashSet};
+use std::cell::Cell;
+
+/*
+Index-range refinement, an access `@load(a, i)` to `a: [T; n]` is safe when
+`0 <= i < n` is implied by the guards it is nested in. Guards are comparisons
+in `if`/`elif` conditions, the negation of the earlier conditions of the chain
+in `elif`/`else`, and the negation of `if` conditions whose body returns for
+the statements following the `if`.
+    i < n      i is below n in the then branch
+    i >= n     i is below n in the else branch, or after `{ return; }`
+The index of `parfun<x in n>` is below `n` throughout the body, the one of
+`reduce<i in n>(..)` within the reduced value. `@thread_idx(d)` is below the
+`max_threads` or `threads` launch bound of dimension `d` when one is declared.
+Terms are compared as text after inlining `let` bindings, `@len(a)` and
+`@len(a, k)` name the extents of `a`. Calls that read memory or have effects,
+`@load`, atomics and functions, give a fresh value every time they run, each
+one becomes a symbol of its own that only a `let` binding it can name again. Thread indices and lengths are never
+negative, sums, products and quotients of non-negative terms are not either.
+*/
+#[derive(Debug, Clone)]
+enum Fact {
+    Lt(String, String),
+    Le(String, String),
+}
+
+impl Fact {
+    fn mentions(&self, id: &str) -> bool {
+        let (lhs, rhs) = match self { Fact::Lt(l, r) | Fact::Le(l, r) => (l, r) };
+        lhs.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.')).chain(rhs.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.')))
+            .any(|t| t.eq(id))
+    }
+}
+
+struct Refinement {
+    threads: Vec<String>,
+    // extents of the shaped arrays in scope, and whether they were declared by the user
+    arrays: HashMap<String, (Vec<String>, bool)>,
+    lets: HashMap<String, BaseExpr>,
+    facts: Vec<Fact>,
+    assigned: HashSet<String>,
+    bounds_check: bool,
+    proven: Vec<SrcLoc>,
+    errors: Vec<String>,
+    // symbols given out for opaque calls so far
+    fresh: Cell<usize>,
+}
+
+// Calls whose value only depends on their arguments and on the thread running them
+const PURE: &[&str] = &["add", "subtract", "multiply", "divide", "@len", "@thread_idx", "@block_idx", "@block_dim", "@grid_dim", "@lane_id"];
+
+fn is_pure(expr: &BaseExpr) -> bool {
+    match expr {
+        BaseExpr::FuncCall(ident, args, _) => PURE.contains(&&ident[..]) && args.iter().all(|a| is_pure(&a.0)),
+        BaseExpr::Member(obj, _) => is_pure(&obj.0),
+        _ => true
+    }
+}
+
+fn constant(term: &str) -> Option<i64> {
+    term.parse::<i64>().ok()
+}
+
+impl Refinement {
+    fn show(&self, expr: &BaseExpr) -> String {
+        show_resolved(&self.term(expr))
+    }
+
+    // `expr` with its `let` bindings inlined and its opaque calls replaced by fresh symbols
+    fn term(&self, expr: &BaseExpr) -> BaseExpr {
+        self.opaque(resolve_lets(expr, &self.lets))
+    }
+
+    fn opaque(&self, expr: BaseExpr) -> BaseExpr {
+        match expr {
+            BaseExpr::FuncCall(ident, args, loc) => if PURE.contains(&&ident[..]) {
+                BaseExpr::FuncCall(ident, args.into_iter().map(|a| (self.opaque(a.0), a.1)).collect(), loc)
+            } else {
+                self.fresh.set(self.fresh.get() + 1);
+                BaseExpr::Ident(format!("{}(..)#{}", ident, self.fresh.get()))
+            },
+            BaseExpr::Member(obj, field) => BaseExpr::Member(Box::new((self.opaque(obj.0), obj.1)), field),
+            other => other
+        }
+    }
+
+    // Facts established by `cond` holding, or by it failing when `negate` is set
+    fn facts_of(&self, cond: &BaseExpr, negate: bool) -> Vec<Fact> {
+        if let BaseExpr::FuncCall(op, args, _) = cond {
+            if args.len() != 2 {
+                return vec![];
+            }
+            let (a, b) = (self.show(&args[0].0), self.show(&args[1].0));
+            return match (&op[..], negate) {
+                ("lt", false) | ("gt_eq", true) => vec![Fact::Lt(a, b)],
+                ("gt", false) | ("lt_eq", true) => vec![Fact::Lt(b, a)],
+                ("lt_eq", false) | ("gt", true) => vec![Fact::Le(a, b)],
+                ("gt_eq", false) | ("lt", true) => vec![Fact::Le(b, a)],
+                ("eq", false) | ("ne", true) => vec![Fact::Le(a.clone(), b.clone()), Fact::Le(b, a)],
+                _ => vec![]
+            };
+        }
+        vec![]
+    }
+
+    // Whether `lhs < rhs` (or `lhs <= rhs` when not `strict`) follows from a single fact or from constants
+    fn ordered(&self, lhs: &str, rhs: &str, strict: bool) -> bool {
+        if let (Some(l), Some(r)) = (constant(lhs), constant(rhs)) {
+            return if strict { l < r } else { l <= r };
+        }
+        if !strict && lhs.eq(rhs) {
+            return true;
+        }
+        // `l < 256` also gives `l <= 255` and `l < 512`
+        let within = |r: &str, lt: bool| match (constant(r), constant(rhs)) {
+            (Some(r), Some(rhs)) => match (lt, strict) {
+                (true, false) => r <= rhs + 1,
+                (false, true) => r < rhs,
+                _ => r <= rhs,
+            },
+            _ => r.eq(rhs) && (lt || !strict),
+        };
+        self.facts.iter().any(|f| match f {
+            Fact::Lt(l, r) => l.eq(lhs) && within(r, true),
+            Fact::Le(l, r) => l.eq(lhs) && within(r, false),
+        })
+    }
+
+    // Whether `lhs < rhs` follows, chaining at most two facts
+    fn below(&self, lhs: &str, rhs: &str) -> bool {
+        if self.ordered(lhs, rhs, true) {
+            return true;
+        }
+        self.facts.iter().any(|f| match f {
+            Fact::Lt(l, mid) => l.eq(lhs) && self.ordered(mid, rhs, false),
+            Fact::Le(l, mid) => l.eq(lhs) && self.ordered(mid, rhs, true),
+        })
+    }
+
+    // `expr` has its `let` bindings resolved already, as for `in_extent`
+    fn nonneg(&self, expr: &BaseExpr) -> bool {
+        let shown = show_resolved(expr);
+        let bounded = self.facts.iter().any(|f| match f {
+            Fact::Lt(l, r) => r.eq(&shown) && constant(l).map(|c| c >= -1).unwrap_or(false),
+            Fact::Le(l, r) => r.eq(&shown) && constant(l).map(|c| c >= 0).unwrap_or(false),
+        });
+        if bounded {
+            return true;
+        }
+        match expr {
+            BaseExpr::ConstantInt(v) => *v >= 0,
+            BaseExpr::Ident(id) => self.threads.contains(id),
+            BaseExpr::FuncCall(ident, args, _) => match &ident[..] {
+                "add" | "multiply" | "divide" => args.iter().all(|a| self.nonneg(&a.0)),
+                "subtract" => args.len() == 2 && self.ordered(&show_resolved(&args[1].0), &show_resolved(&args[0].0), false),
+                "@len" | "@thread_idx" | "@block_idx" | "@block_dim" | "@grid_dim" | "@lane_id" => true,
+                _ => false
+            }
+            _ => false
+        }
+    }
+
+    // Whether `expr` is below any of the names of an extent
+    fn in_extent(&self, expr: &BaseExpr, bounds: &Vec<String>) -> bool {
+        let shown = show_resolved(expr);
+        if bounds.iter().any(|b| self.below(&shown, b)) {
+            return true;
+        }
+        match expr {
+            // shrinking a non-negative term keeps it in range
+            BaseExpr::FuncCall(ident, args, _) if args.len() == 2 => match &ident[..] {
+                "subtract" => self.nonneg(&args[1].0) && self.in_extent(&args[0].0, bounds),
+                "divide" => self.nonneg(&args[0].0) && self.below("0", &show_resolved(&args[1].0)) && self.in_extent(&args[0].0, bounds),
+                _ => false
+            }
+            _ => false
+        }
+    }
+
+    fn check_access(&mut self, intrinsic: &str, args: &Vec<TypedExpr>, loc: &SrcLoc) {
+        let array = match args.first() {
+            Some((BaseExpr::Ident(array), _)) => array.clone(),
+            _ => return
+        };
+        let (extents, declared) = match self.arrays.get(&array) {
+            Some(shape) => shape.clone(),
+            None => return
+        };
+        let indices = match access_indices(intrinsic, args) {
+            Some(indices) => indices,
+            None => return
+        };
+        if indices.len() != extents.len() {
+            // the rank mismatch is reported during code generation
+            return;
+        }
+        let mut unproven = vec![];
+        for (k, (index, extent)) in indices.iter().zip(extents.iter()).enumerate() {
+            let mut bounds = vec![extent.clone(), format!("@len({}, {})", array, k)];
+            if extents.len() == 1 {
+                bounds.push(format!("@len({})", array));
+            }
+            let resolved = self.term(&index.0);
+            if !self.nonneg(&resolved) || !self.in_extent(&resolved, &bounds) {
+                let fixes = self.fixes(&index.0, &resolved, extent);
+                unproven.push((show_resolved(&resolved), extent.clone(), fixes));
+            }
+        }
+        if unproven.is_empty() {
+            self.proven.push(loc.clone());
+        } else if declared && !self.bounds_check {
+            for (index, extent, fixes) in unproven {
+                self.errors.push(format!("{}: cannot prove that index `{}` of `{}` is within `0..{}`, {} or compile with --bounds-check",
+                                         loc, index, array, extent, fixes));
+            }
+        }
+    }
+
+    // Ways to make an index provably in range. The left side of a comparison cannot be
+    // an arithmetic expression, and an opaque call is another value in the guard, such
+    // an index has to be bound with `let` to be guarded
+    fn fixes(&self, index: &BaseExpr, resolved: &BaseExpr, extent: &str) -> String {
+        let operand = match index {
+            BaseExpr::FuncCall(ident, _, _) if ["add", "subtract", "multiply", "divide"].contains(&&ident[..]) => None,
+            _ if !is_pure(index) => None,
+            _ => Some(show_resolved(index)),
+        };
+        let name = operand.clone().unwrap_or(String::from("k"));
+        let mut guards = vec![];
+        if !self.nonneg(resolved) {
+            guards.push(format!("`if {} >= 0`", name));
+        }
+        guards.push(format!("`if {} < {}`", name, extent));
+        let mut fixes = vec![match operand {
+            Some(_) => format!("guard the access with {}", guards.join(" and ")),
+            None => format!("bind the index with `let {} = ..` and guard the access with {}", name, guards.join(" and ")),
+        }];
+        // `@thread_idx(d)` is below the launch bound
+        if let (BaseExpr::FuncCall(ident, args, _), Some(_)) = (resolved, constant(extent)) {
+            if let Some((BaseExpr::ConstantInt(dim), _)) = args.first().filter(|_| ident.eq("@thread_idx")) {
+                fixes.push(format!("declare at most {} threads in dimension {} with the `max_threads` launch bound", extent, dim));
+            }
+        }
+        fixes.join(", ")
+    }
+
+    fn visit(&mut self, expr: &BaseExpr) {
+        match expr {
+            BaseExpr::FuncCall(ident, args, loc) => {
+                for arg in args.iter() {
+                    self.visit(&arg.0);
+                }
+                if access_indices(&ident[..], args).is_some() {
+                    self.check_access(&ident[..], args, loc);
+                }
+            }
+            BaseExpr::SharedDecl(id, ty) => {
+                match shape_of(ty) {
+                    Some((extents, _)) => self.arrays.insert(id.clone(), (extents, true)),
+                    None => self.arrays.remove(id)
+                };
+            }
+            BaseExpr::LetDecl(id, _, value) => {
+                self.visit(&value.0);
+                self.arrays.remove(id);
+                let value = self.term(&value.0);
+                self.lets.insert(id.clone(), value);
+                // the facts about an earlier `id` say nothing about the new one
+                self.facts.retain(|f| !f.mentions(id));
+            }
+            BaseExpr::Assign(id, value) => {
+                self.visit(&value.0);
+                self.lets.remove(id);
+                self.facts.retain(|f| !f.mentions(id));
+                self.assigned.insert(id.clone());
+            }
+            BaseExpr::Reduce { index, range, value, op, target, loc } => {
+                self.visit(&range.0);
+                for arg in target.iter() {
+                    self.visit(&arg.0);
+                }
+                // the target is updated like by the atomic of `op`
+                if !target.is_empty() {
+                    let mut args = target.clone();
+                    args.push((**value).clone());
+                    self.check_access(&format!("@atomic_{}", op), &args, loc);
+                }
+                let saved = (self.facts.clone(), self.lets.clone(), self.threads.clone());
+                let bound = Fact::Lt(index.clone(), self.show(&range.0));
+                self.facts.retain(|f| !f.mentions(index));
+                self.facts.push(bound);
+                self.lets.remove(index);
+                self.threads.push(index.clone());
+                self.visit(&value.0);
+                let (facts, lets, threads) = saved;
+                self.facts = facts;
+                self.lets = lets;
+                self.threads = threads;
+            }
+            BaseExpr::Return(value) => self.visit(&value.0),
+            BaseExpr::Member(obj, _) => self.visit(&obj.0),
+            BaseExpr::IfExpr(cond, body, next) | BaseExpr::Else(Some(cond), body, next) => {
+                self.visit(&cond.0);
+                let holds = self.facts_of(&cond.0, false);
+                let fails = self.facts_of(&cond.0, true);
+                self.walk(body, holds);
+                if let Some(next) = next {
+                    self.walk(&vec![(**next).clone()], fails);
+                }
+            }
+            BaseExpr::Else(None, body, _) => self.walk(body, vec![]),
+            _ => {}
+        }
+    }
+
+    // Walks a nested body under extra facts, which no longer hold once it is left
+    fn walk(&mut self, body: &Vec<TypedExpr>, facts: Vec<Fact>) {
+        let saved = (self.facts.clone(), self.lets.clone(), self.arrays.clone());
+        self.facts.extend(facts);
+        self.walk_body(body);
+        let (mut facts, lets, arrays) = saved;
+        let assigned = &self.assigned;
+        facts.retain(|f| !assigned.iter().any(|id| f.mentions(id)));
+        self.facts = facts;
+        self.lets = lets;
+        self.arrays = arrays;
+    }
+
+    fn walk_body(&mut self, body: &Vec<TypedExpr>) {
+        for expr in body.iter() {
+            self.visit(&expr.0);
+            // `if i >= n { return; }` guards everything after it
+            if let BaseExpr::IfExpr(cond, then_body, None) = &expr.0 {
+                if let Some((BaseExpr::Return(_), _)) | Some((BaseExpr::RetNull, _)) = then_body.last() {
+                    let fails = self.facts_of(&cond.0, true);
+                    self.facts.extend(fails);
+                }
+            }
+        }
+    }
+}
+
+// Accesses to arrays with a declared shape must be provably in range unless bounds are checked at runtime,
+// the proven ones are recorded so that they are never checked
+pub fn check_bounds(func: &BaseExpr, bounds_check: bool, proven: &mut HashSet<SrcLoc>) -> Vec<String> {
+    if let BaseExpr::FuncDecl { para_in, ranges, is_par, launch, params, body, .. } = func {
+        let mut arrays = HashMap::new();
+        for (name, ty) in params.iter() {
+            if let Some((extents, _)) = shape_of(ty) {
+                arrays.insert(name.clone(), (extents, true));
+            }
+        }
+        if *is_par {
+            for (name, ty) in shape_kernel_arrays(params.clone()) {
+                if let Some((extents, _)) = shape_of(&ty) {
+                    arrays.entry(name).or_insert((extents, false));
+                }
+            }
+        }
+        let mut refinement = Refinement {
+            threads: para_in.iter().filter(|p| p.ne(&"_")).cloned().collect(),
+            arrays,
+            lets: HashMap::new(),
+            facts: vec![],
+            assigned: HashSet::new(),
+            bounds_check,
+            proven: vec![],
+            errors: vec![],
+            fresh: Cell::new(0),
+        };
+        // the body of `parfun<x in n>` only runs for `x < n`
+        for (thread, range) in para_in.iter().zip(ranges.iter()) {
+            if let Some(range) = range.as_ref().filter(|_| thread.ne("_")) {
+                let fact = Fact::Lt(thread.clone(), refinement.show(&range.0));
+                refinement.facts.push(fact);
+            }
+        }
+        // blocks run at most `max_threads` threads, exactly `threads` if given, dimensions left out hold one
+        for (_, values) in launch.iter().filter(|l| l.0.eq("max_threads") || l.0.eq("threads")) {
+            for dim in 0..3 {
+                let fact = Fact::Lt(format!("@thread_idx({})", dim), values.get(dim).cloned().unwrap_or(1).to_string());
+                refinement.facts.push(fact);
+            }
+        }
+        refinement.walk_body(body);
+        proven.extend(refinement.proven);
+        return refinement.errors;
+    }
+    vec![]
+}
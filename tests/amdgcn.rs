@@ -0,0 +1,56 @@
+mod common;
+
+use common::*;
+
+const LAUNCH: &str = "import src/arith.ru
+
+parfun<x> @[max_threads(256), min_blocks(2), max_registers(32)] hot(a: mut [f32]) {
+    if x < @len(a) {
+        @store(a, x, @load(a, x) + @load(a, x));
+    }
+    return;
+}
+
+parfun<x, y> @[threads(16, 16)] tiled(a: mut [f32]) {
+    return;
+}
+";
+
+#[test]
+fn kernels_use_the_amdgpu_calling_convention() {
+    let compiled = compile_ok("amdgcn_kernels", LAUNCH, &["--target", "amdgcn"]);
+    assert!(compiled.ir.contains("target triple = \"amdgcn-amd-amdhsa\""));
+    assert!(compiled.ir.contains("define amdgpu_kernel void @hot(float addrspace(1)* %a, i64 %a.len)"), "{}", compiled.ir);
+    assert!(!compiled.ir.contains("nvvm"), "{}", compiled.ir);
+    assert!(assembles(&compiled, "amdgcn", "gfx900"));
+}
+
+#[test]
+fn launch_bounds_become_function_attributes() {
+    let compiled = compile_ok("amdgcn_launch", LAUNCH, &["--target", "amdgcn"]);
+    assert!(compiled.ir.contains("\"amdgpu-flat-work-group-size\"=\"1,256\" \"amdgpu-num-vgpr\"=\"32\" \"amdgpu-waves-per-eu\"=\"2\""), "{}", compiled.ir);
+    assert!(compiled.ir.contains("\"amdgpu-flat-work-group-size\"=\"256,256\""), "{}", compiled.ir);
+    assert!(!compiled.stderr.contains("warning"), "{}", compiled.stderr);
+}
+
+#[test]
+fn reductions_synchronize_the_workgroup() {
+    let compiled = compile_ok("amdgcn_reduce", "import src/arith.ru
+
+parfun<x> @[max_threads(64)] sum(a: [f32; n], out: mut [f32; 1]) {
+    let s = reduce<i in n>(@load(a, i), add, out, 0);
+}
+", &["--target", "amdgcn"]);
+    assert!(compiled.ir.contains("internal addrspace(3) global [64 x float] undef"), "{}", compiled.ir);
+    assert!(compiled.ir.contains("call void @llvm.amdgcn.s.barrier()"), "{}", compiled.ir);
+    assert!(compiled.ir.contains("fence syncscope(\"workgroup\") release"), "{}", compiled.ir);
+    assert!(assembles(&compiled, "amdgcn", "gfx900"));
+}
+
+#[test]
+fn target_errors_go_to_stderr() {
+    let err = compile_err("amdgcn_unknown_target", LAUNCH, &["--target", "spirv"]);
+    assert!(err.contains("Unknown target : spirv, expected nvptx or amdgcn"), "{}", err);
+    let err = compile_err("amdgcn_libdevice", LAUNCH, &["--target", "amdgcn", "--libdevice", "libdevice.10.bc"]);
+    assert!(err.contains("libdevice is NVIDIA bitcode, it cannot be linked for amdgcn"), "{}", err);
+}
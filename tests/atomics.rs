@@ -0,0 +1,107 @@
+mod common;
+
+use common::*;
+
+fn kernel(name: &str, params: &str, body: &str, flags: &[&str]) -> Compiled {
+    compile(name, &format!("import src/arith.ru
+
+parfun<x> update({}) {{
+    {}
+    return;
+}}
+", params, body), flags)
+}
+
+fn update(name: &str, params: &str, body: &str) -> String {
+    let compiled = kernel(name, params, body, &[]);
+    assert!(compiled.success, "`{}` failed to compile:\n{}", name, compiled.stderr);
+    assert!(assembles(&compiled, "nvptx64", "sm_70"));
+    function(&compiled, "update").to_string()
+}
+
+fn rejected(name: &str, params: &str, body: &str) -> String {
+    let compiled = kernel(name, params, body, &[]);
+    assert!(!compiled.success, "`{}` compiled but was expected to fail", name);
+    compiled.stderr
+}
+
+#[test]
+fn integer_atomics_are_read_modify_writes() {
+    let ir = update("atomics_integer", "c: mut [i64; 8], v: i64", "@atomic_add(c, 0, v);
+    @atomic_min(c, 1, v);
+    @atomic_max(c, 2, v);
+    @atomic_xchg(c, 3, v);
+    @atomic_and(c, 4, v);
+    @atomic_or(c, 5, v);
+    @atomic_xor(c, 6, v);");
+    for op in ["add", "min", "max", "xchg", "and", "or", "xor"].iter() {
+        assert!(ir.contains(&format!("atomicrmw {} i64 addrspace(1)*", op)), "no {} in\n{}", op, ir);
+    }
+    assert!(!ir.contains("cmpxchg"), "{}", ir);
+}
+
+#[test]
+fn literals_take_the_element_type() {
+    let ir = update("atomics_literal", "c: mut [i32; 1]", "@atomic_add(c, 0, 1);");
+    assert!(ir.contains("atomicrmw add i32 addrspace(1)* %c, i32 1 monotonic"), "{}", ir);
+}
+
+#[test]
+fn float_addition_is_a_read_modify_write() {
+    let ir = update("atomics_fadd", "f: mut [f32; 1], d: mut [f64; 1], v: f32, w: f64", "@atomic_add(f, 0, v);
+    @atomic_add(d, 0, w);");
+    assert!(ir.contains("atomicrmw fadd float addrspace(1)* %f, float %v monotonic"), "{}", ir);
+    assert!(ir.contains("atomicrmw fadd double addrspace(1)* %d, double %w monotonic"), "{}", ir);
+}
+
+#[test]
+fn float_minimum_and_maximum_retry_a_compare_and_swap() {
+    let ir = update("atomics_cas_loop", "f: mut [f32; 2], v: f32", "@atomic_min(f, 0, v);
+    @atomic_max(f, 1, v, seq_cst);");
+    assert!(ir.contains("%bitsptrtmp = bitcast float addrspace(1)* %f to i32 addrspace(1)*"), "{}", ir);
+    assert!(ir.contains("%expectedtmp = phi i32 [ %firsttmp, %entry ], [ %seentmp, %casloop ]"), "{}", ir);
+    assert!(ir.contains("fcmp olt float %v, %currenttmp"), "{}", ir);
+    assert!(ir.contains("fcmp ogt float %v, %currenttmp"), "{}", ir);
+    assert!(ir.contains("cmpxchg i32 addrspace(1)* %bitsptrtmp, i32 %expectedtmp, i32 %bitstmp monotonic monotonic"), "{}", ir);
+    assert!(ir.contains("seq_cst seq_cst"), "{}", ir);
+    assert!(ir.contains("br i1 %swappedtmp, label %casdone, label %casloop"), "{}", ir);
+    assert!(!ir.contains("atomicrmw"), "{}", ir);
+}
+
+#[test]
+fn compare_and_swap_goes_through_the_bits_of_floats() {
+    let ir = update("atomics_cas", "c: mut [i64; 1], f: mut [f32; 1], v: f32, old: i64", "@atomic_cas(c, 0, 0, old);
+    @atomic_cas(f, 0, v, v, acq_rel);");
+    assert!(ir.contains("cmpxchg i64 addrspace(1)* %c, i64 0, i64 %old monotonic monotonic"), "{}", ir);
+    assert!(ir.contains("bitcast float %v to i32"), "{}", ir);
+    // a failed swap stores nothing, it only acquires
+    assert!(ir.contains("i32 %bitstmp, i32 %bitstmp acq_rel acquire"), "{}", ir);
+}
+
+#[test]
+fn orderings_are_relaxed_by_default() {
+    let ir = update("atomics_orderings", "c: mut [i64; 5], v: i64", "@atomic_add(c, 0, v);
+    @atomic_add(c, 1, v, acquire);
+    @atomic_add(c, 2, v, release, block);
+    @atomic_add(c, 3, v, acq_rel, device);
+    @atomic_add(c, 4, v, seq_cst, system);");
+    for ordering in ["monotonic", "acquire", "release", "acq_rel", "seq_cst"].iter() {
+        assert!(ir.contains(&format!("i64 %v {}, align 8", ordering)), "no {} in\n{}", ordering, ir);
+    }
+}
+
+#[test]
+fn immutable_arrays_are_not_updated() {
+    let err = rejected("atomics_immutable", "c: [i64; 1], v: i64", "@atomic_add(c, 0, v);");
+    assert!(err.contains("`@atomic_add` cannot write into immutable array `c` of type [i64; 1], declare it as `mut`"), "{}", err);
+}
+
+#[test]
+fn element_types_are_checked() {
+    let err = rejected("atomics_bits_of_floats", "f: mut [f32; 1], v: f32", "@atomic_and(f, 0, v);");
+    assert!(err.contains("`@atomic_and` is not available on elements of type f32, only on i32, i64"), "{}", err);
+    let err = rejected("atomics_narrowing", "c: mut [i32; 1], v: f64", "@atomic_add(c, 0, v);");
+    assert!(err.contains("`@atomic_add` cannot combine f64 with an element of type i32"), "{}", err);
+    let err = rejected("atomics_orderings_twice", "c: mut [i64; 1], v: i64", "@atomic_add(c, 0, v, acquire, seq_cst);");
+    assert!(err.contains("`@atomic_add` is given both `acquire` and `seq_cst`, only one ordering is allowed"), "{}", err);
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::*;
+
+const SHIFT: &str = "import src/arith.ru
+
+parfun<x> shift(a: mut [i64], k: i64) {
+    @store(a, x + k, 1);
+    return;
+}
+";
+
+#[test]
+fn unproven_indices_are_guarded_at_runtime() {
+    let checked = compile_ok("bounds_shift", SHIFT, &["--bounds-check"]);
+    let shift = function(&checked, "shift");
+    assert!(shift.contains("define void @shift(i64 addrspace(1)* %a, i64 %k, i64 %a.len)"), "{}", shift);
+    assert!(shift.contains("%boundtmp = icmp ult i64 %tmp.i, %a.len"), "{}", shift);
+    assert!(shift.contains("br i1 %boundtmp, label %inbounds, label %outofbounds"), "{}", shift);
+    assert!(shift.contains("x i8]* @oobmsg, i32 0, i32 0), i8* null)"), "{}", shift);
+    assert!(shift.contains("%printtmp = call i32 @vprintf("), "{}", shift);
+    assert!(shift.contains("call void @llvm.trap()\n  unreachable"), "{}", shift);
+    // the location is printed with a newline and terminated for vprintf
+    let location = format!("{}:4:5: index out of bounds in `@store`", checked.path.with_extension("ru").display());
+    let message = format!("@oobmsg = private unnamed_addr constant [{} x i8] c\"{}\\0A\\00\"", location.len() + 2, location);
+    assert!(checked.ir.contains(&message), "no {} in\n{}", message, checked.ir);
+    assert!(assembles(&checked, "nvptx64", "sm_70"));
+}
+
+#[test]
+fn amdgcn_traps_without_printing() {
+    let checked = compile_ok("bounds_shift_amdgcn", SHIFT, &["--target", "amdgcn", "--bounds-check"]);
+    let shift = function(&checked, "shift");
+    assert!(shift.contains("call void @llvm.trap()"), "{}", shift);
+    assert!(!checked.ir.contains("printf"), "{}", checked.ir);
+}
+
+#[test]
+fn proven_indices_are_not_guarded() {
+    let checked = compile_ok("bounds_proven", "import src/arith.ru
+
+fun first(a: mut [i64; n]) {
+    if n > 0 {
+        @store(a, 0, 1);
+    }
+    return;
+}
+", &["--bounds-check"]);
+    assert!(!function(&checked, "first").contains("@llvm.trap()"), "{}", checked.ir);
+}
+
+#[test]
+fn lengths_are_the_hidden_extent_parameters() {
+    let compiled = compile_ok("bounds_len", "import src/arith.ru
+
+fun size(a: [i64; n]) -> i64 {
+    return @len(a);
+}
+", &[]);
+    let size = function(&compiled, "size");
+    assert!(size.contains("define i64 @size(i64* %a, i64 %n)"), "{}", size);
+    assert!(size.contains("ret i64 %n"), "{}", size);
+
+    let err = compile_err("bounds_len_unknown", "import src/arith.ru
+
+fun size(a: [i64]) -> i64 {
+    return @len(a);
+}
+", &[]);
+    assert!(err.contains("`@len` length of array of type [i64] is unknown, declare its shape as `[T; n]`"), "{}", err);
+}
@@ -0,0 +1,72 @@
+// Compiles ruda sources with the built compiler, tests look at the diagnostics and the emitted IR
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use std::process::Command;
+
+pub struct Compiled {
+    pub success: bool,
+    pub stderr: String,
+    pub ir: String,
+    pub path: PathBuf,
+}
+
+// Sources are written under the temporary directory and compiled from the crate root, so that
+// `import src/arith.ru` resolves. `name` has to be unique across the tests
+pub fn compile(name: &str, source: &str, flags: &[&str]) -> Compiled {
+    let dir = std::env::temp_dir().join("ruda-tests");
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join(format!("{}.ru", name));
+    let output = dir.join(format!("{}.ll", name));
+    let _ = std::fs::remove_file(&output);
+    std::fs::write(&input, source).unwrap();
+    let result = Command::new(env!("CARGO_BIN_EXE_ruda"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(flags)
+        .arg(&input)
+        .arg("-o")
+        .arg(&output)
+        .output()
+        .unwrap();
+    Compiled {
+        success: result.status.success(),
+        stderr: String::from_utf8_lossy(&result.stderr).into_owned(),
+        ir: std::fs::read_to_string(&output).unwrap_or_default(),
+        path: output,
+    }
+}
+
+pub fn compile_ok(name: &str, source: &str, flags: &[&str]) -> Compiled {
+    let compiled = compile(name, source, flags);
+    assert!(compiled.success, "`{}` failed to compile:\n{}", name, compiled.stderr);
+    compiled
+}
+
+// The message of a rejected source, the panic of the compiler or the checks it reported
+pub fn compile_err(name: &str, source: &str, flags: &[&str]) -> String {
+    let compiled = compile(name, source, flags);
+    assert!(!compiled.success, "`{}` compiled but was expected to fail", name);
+    compiled.stderr
+}
+
+// The body of the function `name` in the emitted IR
+pub fn function<'a>(compiled: &'a Compiled, name: &str) -> &'a str {
+    let start = compiled.ir.find(&format!("@{}(", name)).and_then(|at| compiled.ir[..at].rfind("define"))
+        .unwrap_or_else(|| panic!("no function `{}` in\n{}", name, compiled.ir));
+    let end = compiled.ir[start..].find("\n}").map(|end| start + end + 2).unwrap_or(compiled.ir.len());
+    &compiled.ir[start..end]
+}
+
+// Runs llc over the IR when an LLVM 14 one is installed, the textual checks still apply without it
+pub fn assembles(compiled: &Compiled, march: &str, mcpu: &str) -> bool {
+    let status = Command::new("llc-14")
+        .arg(format!("-march={}", march))
+        .arg(format!("-mcpu={}", mcpu))
+        .arg(&compiled.path)
+        .args(["-o", "/dev/null"])
+        .status();
+    match status {
+        Ok(status) => status.success(),
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn missing_returns_are_reported() {
+    let err = compile_err("flow_missing_return", "import src/arith.ru
+
+fun sign(a: i64) -> i64 {
+    if a < 0 {
+        return 0 - 1;
+    } elif a > 0 {
+        return 1;
+    }
+}
+", &[]);
+    assert!(err.contains("Function `sign` returns i64, but not all paths return a value"), "{}", err);
+}
+
+#[test]
+fn chains_ending_in_else_return() {
+    let compiled = compile_ok("flow_else_returns", "import src/arith.ru
+
+fun sign(a: i64) -> i64 {
+    if a < 0 {
+        return 0 - 1;
+    } elif a > 0 {
+        return 1;
+    } else {
+        return 0;
+    }
+}
+", &[]);
+    assert!(compiled.ir.contains("define i64 @sign("));
+    assert!(assembles(&compiled, "nvptx64", "sm_70"));
+}
+
+#[test]
+fn unreachable_statements_are_dropped() {
+    let compiled = compile_ok("flow_unreachable", "import src/arith.ru
+
+fun first(a: [i64]) -> i64 {
+    return @load(a, 0);
+    @load(a, 1);
+}
+
+parfun<i> clear(a: mut [i64]) {
+    @store(a, i, 0);
+    return;
+    @store(a, i, 1);
+}
+", &[]);
+    assert!(compiled.stderr.contains("warning: unreachable code in `first`, 1 statement(s) after a return are ignored"), "{}", compiled.stderr);
+    assert!(compiled.stderr.contains("warning: unreachable code in `clear`, 1 statement(s) after a return are ignored"), "{}", compiled.stderr);
+    assert!(!function(&compiled, "clear").contains("store i64 1"));
+    assert!(assembles(&compiled, "nvptx64", "sm_70"));
+}
+
+#[test]
+fn unit_functions_may_fall_off_their_end() {
+    let compiled = compile_ok("flow_unit", "import src/arith.ru
+
+parfun<i> clear(a: mut [i64]) {
+    if i < 4 {
+        @store(a, i, 0);
+    }
+}
+", &[]);
+    assert!(function(&compiled, "clear").contains("ret void"));
+    assert!(assembles(&compiled, "nvptx64", "sm_70"));
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn indices_past_the_second_are_folded_onto_z() {
+    let compiled = compile_ok("folding_four", "import src/arith.ru
+
+parfun<a, b, c, d> fill(x: mut [i64]) {
+    @store(x, a, b + c + d);
+    return;
+}
+", &[]);
+    let fill = function(&compiled, "fill");
+    assert!(fill.contains("define void @fill(i64 addrspace(1)* %x, i64 %x.len, i64 %c.extent, i64 %d.extent)"), "{}", fill);
+    assert!(fill.contains("%foldedtmp = add nuw i64 %offsettmp17, %tidtmp16"), "{}", fill);
+    assert!(fill.contains("%d = udiv i64 %foldedtmp, %c.extent"), "{}", fill);
+    assert!(fill.contains("%c = urem i64 %foldedtmp, %c.extent"), "{}", fill);
+    assert!(fill.contains("%insidetmp = icmp ult i64 %d, %d.extent"), "{}", fill);
+    assert!(fill.contains("br i1 %insidetmp, label %folded"), "{}", fill);
+    assert!(assembles(&compiled, "nvptx64", "sm_70"));
+}
+
+#[test]
+fn every_folded_index_takes_the_remainder_of_its_extent() {
+    let compiled = compile_ok("folding_five", "import src/arith.ru
+
+parfun<a, b, c, d, e> fill(x: mut [i64]) {
+    @store(x, a, b + c + d + e);
+    return;
+}
+", &[]);
+    let fill = function(&compiled, "fill");
+    assert!(fill.contains("i64 %c.extent, i64 %d.extent, i64 %e.extent)"), "{}", fill);
+    assert!(fill.contains("%c = urem i64 %foldedtmp, %c.extent"), "{}", fill);
+    assert!(fill.contains("%d = urem i64 %foldedtmp18, %d.extent"), "{}", fill);
+    assert!(fill.contains("%e = udiv i64 %foldedtmp18, %d.extent"), "{}", fill);
+    assert!(fill.contains("%insidetmp = icmp ult i64 %e, %e.extent"), "{}", fill);
+}
+
+#[test]
+fn folded_indices_cannot_loop_over_a_range() {
+    let err = compile_err("folding_range", "import src/arith.ru
+
+parfun<a, b, c in n, d> fill(x: mut [i64], n: i64) {
+    @store(x, a, b + c + d);
+    return;
+}
+", &[]);
+    assert!(err.contains("Kernel `fill`: `c` is folded onto z with the other indices after the second, it cannot loop over a range"), "{}", err);
+}
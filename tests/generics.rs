@@ -0,0 +1,121 @@
+mod common;
+
+use common::*;
+
+const AXPY: &str = "import src/arith.ru
+
+fun axpy<T: f32 | f64>(a: T, x: T, y: T) -> T {
+    return a * x + y;
+}
+
+parfun<i> vaxpy<T: f32 | f64>(a: T, x: [T], y: mut [T]) {
+    @store(y, i, axpy(a, @load(x, i), @load(y, i)));
+    return;
+}
+
+instance saxpy = vaxpy<f32>;
+instance daxpy = vaxpy<f64>;
+
+fun twice(a: i32, b: f32) -> f32 {
+    return axpy(a, b, b);
+}
+";
+
+#[test]
+fn instances_are_generated_per_type_argument() {
+    let compiled = compile_ok("generics_instances", AXPY, &[]);
+    assert!(compiled.ir.contains("define float @axpy_f32("));
+    assert!(compiled.ir.contains("define double @axpy_f64("));
+    assert!(function(&compiled, "saxpy").contains("float addrspace(1)*"));
+    assert!(function(&compiled, "daxpy").contains("double addrspace(1)*"));
+    assert!(assembles(&compiled, "nvptx64", "sm_70"));
+}
+
+#[test]
+fn inference_casts_arguments_to_the_instance() {
+    let compiled = compile_ok("generics_inference", AXPY, &[]);
+    let twice = function(&compiled, "twice");
+    assert!(twice.contains("sitofp i32 %a to float"));
+    assert!(!twice.contains("double"));
+}
+
+#[test]
+fn unbounded_type_parameters_have_no_operators() {
+    let err = compile_err("generics_unbounded", "import src/arith.ru
+
+fun sum<T>(a: T, b: T) -> T {
+    return a + b;
+}
+", &[]);
+    assert!(err.contains("`T` does not implement interface `Num`, which provides `add`"), "{}", err);
+}
+
+#[test]
+fn overloads_take_the_fewest_subtyping_steps() {
+    let compiled = compile_ok("generics_overload", "fun pick(a: f64) -> f64 {
+    return a;
+}
+
+fun pick(a: i64) -> i64 {
+    return a;
+}
+
+fun widen(a: i32) -> i64 {
+    return pick(a);
+}
+", &[]);
+    let widen = function(&compiled, "widen");
+    assert!(widen.contains("sext i32 %a to i64"), "{}", widen);
+    assert!(!widen.contains("double"), "{}", widen);
+}
+
+#[test]
+fn records_implement_interfaces() {
+    let compiled = compile_ok("generics_records", "import src/arith.ru
+
+type Complex = { re: f32, im: f32 };
+
+impl Num for Complex {
+    fun add(a: Complex, b: Complex) -> Complex {
+        return Complex(a.re + b.re, a.im + b.im);
+    }
+    fun subtract(a: Complex, b: Complex) -> Complex {
+        return Complex(a.re - b.re, a.im - b.im);
+    }
+    fun multiply(a: Complex, b: Complex) -> Complex {
+        return Complex(a.re * b.re - a.im * b.im, a.re * b.im + a.im * b.re);
+    }
+    fun divide(a: Complex, b: Complex) -> Complex {
+        let d = b.re * b.re + b.im * b.im;
+        let re = a.re * b.re + a.im * b.im;
+        let im = a.im * b.re - a.re * b.im;
+        return Complex(re / d, im / d);
+    }
+}
+
+fun axpy<T: Num>(a: T, x: T, y: T) -> T {
+    return a * x + y;
+}
+
+fun caxpy(a: Complex, x: Complex, y: Complex) -> Complex {
+    return axpy(a, x, y);
+}
+", &[]);
+    assert!(compiled.ir.contains("define %Complex @axpy_Complex("));
+    assert!(assembles(&compiled, "nvptx64", "sm_70"));
+}
+
+#[test]
+fn missing_interface_methods_are_reported() {
+    let err = compile_err("generics_missing_impl", "import src/arith.ru
+
+type Pair = { a: i32, b: i32 };
+
+impl Num for Pair {
+    fun add(x: Pair, y: Pair) -> Pair {
+        return Pair(x.a + y.a, x.b + y.b);
+    }
+}
+", &[]);
+    assert!(err.contains("`impl Num for Pair` is missing `fun subtract"), "{}", err);
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use common::*;
+
+const HALF: &str = "import src/arith.ru
+
+fun hadd(a: f16, b: f16) -> f16 {
+    return a + b;
+}
+
+fun hvec(a: f16x2, b: f16x2) -> f16x2 {
+    return a - b;
+}
+
+fun bmul(a: bf16, b: bf16) -> bf16 {
+    return a * b;
+}
+
+fun round(a: f64) -> bf16 {
+    return bfloat(a);
+}
+";
+
+fn promoted(compiled: &Compiled) {
+    let hadd = function(compiled, "hadd");
+    assert!(hadd.contains("%halftmp = fpext half %a to float"), "{}", hadd);
+    assert!(hadd.contains("%halftmp2 = fadd float %halftmp, %halftmp1"), "{}", hadd);
+    assert!(hadd.contains("%halftmp3 = fptrunc float %halftmp2 to half"), "{}", hadd);
+    let hvec = function(compiled, "hvec");
+    assert!(hvec.contains("fsub <2 x float> %halftmp, %halftmp1"), "{}", hvec);
+    assert!(hvec.contains("fptrunc <2 x float> %vectmp to <2 x half>"), "{}", hvec);
+}
+
+fn native(compiled: &Compiled) {
+    let hadd = function(compiled, "hadd");
+    assert!(hadd.contains("%halftmp = fadd half %a, %b"), "{}", hadd);
+    assert!(!hadd.contains("fpext"), "{}", hadd);
+    assert!(function(compiled, "hvec").contains("%vectmp = fsub <2 x half> %a, %b"));
+}
+
+#[test]
+fn half_is_computed_in_single_precision_without_native_arithmetic() {
+    let compiled = compile_ok("half_default", HALF, &[]);
+    promoted(&compiled);
+    assert!(assembles(&compiled, "nvptx64", "sm_50"));
+    promoted(&compile_ok("half_sm_50", HALF, &["--mcpu", "sm_50"]));
+    promoted(&compile_ok("half_amdgcn", HALF, &["--target", "amdgcn"]));
+}
+
+#[test]
+fn half_is_native_where_the_target_has_it() {
+    let compiled = compile_ok("half_sm_70", HALF, &["--mcpu", "sm_70"]);
+    native(&compiled);
+    assert!(assembles(&compiled, "nvptx64", "sm_70"));
+    native(&compile_ok("half_gfx900", HALF, &["--target", "amdgcn", "--mcpu", "gfx900"]));
+}
+
+#[test]
+fn bfloat_is_widened_and_rounded_to_nearest_even() {
+    for (name, flags) in [("bfloat_default", &[][..]), ("bfloat_sm_70", &["--mcpu", "sm_70"][..])].iter() {
+        let compiled = compile_ok(name, HALF, flags);
+        let bmul = function(&compiled, "bmul");
+        assert!(bmul.contains("define i16 @bmul(i16 %a, i16 %b)"), "{}", bmul);
+        assert!(bmul.contains("%bf16tmp1 = shl i32 %bf16tmp, 16\n  %bf16tmp2 = bitcast i32 %bf16tmp1 to float"), "{}", bmul);
+        assert!(bmul.contains("%halftmp = fmul float %bf16tmp2, %bf16tmp5"), "{}", bmul);
+        // ties go to the even result, the lowest kept bit is added on top of the bias
+        assert!(bmul.contains("%bf16tmp7 = lshr i32 %bf16tmp6, 16
+  %bf16tmp8 = and i32 %bf16tmp7, 1
+  %bf16tmp9 = add i32 %bf16tmp6, 32767
+  %bf16tmp10 = add i32 %bf16tmp9, %bf16tmp8
+  %bf16tmp11 = lshr i32 %bf16tmp10, 16
+  %bf16tmp12 = trunc i32 %bf16tmp11 to i16"), "{}", bmul);
+        // NaN is kept quiet instead of being rounded into infinity
+        assert!(bmul.contains("%bf16tmp13 = fcmp uno float %halftmp, %halftmp
+  %bf16tmp14 = select i1 %bf16tmp13, i16 32704, i16 %bf16tmp12"), "{}", bmul);
+    }
+    let compiled = compile_ok("bfloat_round", HALF, &[]);
+    let round = function(&compiled, "round");
+    assert!(round.contains("%convtmp = fptrunc double %a to float\n  %bf16tmp = bitcast float %convtmp to i32"), "{}", round);
+    assert!(round.contains("add i32 %bf16tmp, 32767"), "{}", round);
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use common::*;
+
+const LAUNCH: &str = "import src/arith.ru
+
+parfun<x> @[BOUNDS] hot(a: mut [f32]) {
+    if x < @len(a) {
+        @store(a, x, @load(a, x) + @load(a, x));
+    }
+    return;
+}
+
+parfun<x> plain(a: mut [f32]) {
+    return;
+}
+";
+
+#[test]
+fn launch_bounds_become_nvvm_annotations() {
+    let compiled = compile_ok("launch_nvvm", &LAUNCH.replace("BOUNDS", "max_threads(256), min_blocks(2)"), &[]);
+    assert!(compiled.ir.contains("@hot, !\"kernel\", i32 1}"), "{}", compiled.ir);
+    assert!(compiled.ir.contains("@hot, !\"maxntidx\", i32 256}"), "{}", compiled.ir);
+    assert!(compiled.ir.contains("@hot, !\"minctasm\", i32 2}"), "{}", compiled.ir);
+    assert!(!compiled.ir.contains("@plain, !\"maxntidx\""), "{}", compiled.ir);
+    assert_eq!(compiled.ir.matches("!\"maxntid").count(), 1, "{}", compiled.ir);
+    assert!(assembles(&compiled, "nvptx64", "sm_70"));
+}
+
+#[test]
+fn every_dimension_of_a_bound_is_annotated() {
+    let compiled = compile_ok("launch_dims", &LAUNCH.replace("BOUNDS", "threads(16, 8, 2), max_registers(32)"), &[]);
+    assert!(compiled.ir.contains("@hot, !\"reqntidx\", i32 16}"), "{}", compiled.ir);
+    assert!(compiled.ir.contains("@hot, !\"reqntidy\", i32 8}"), "{}", compiled.ir);
+    assert!(compiled.ir.contains("@hot, !\"reqntidz\", i32 2}"), "{}", compiled.ir);
+    assert!(compiled.ir.contains("@hot, !\"maxnreg\", i32 32}"), "{}", compiled.ir);
+}
+
+#[test]
+fn inconsistent_launch_bounds_are_rejected() {
+    let err = compile_err("launch_min_blocks", &LAUNCH.replace("BOUNDS", "min_blocks(2)"), &[]);
+    assert!(err.contains("Kernel `hot`: `min_blocks` needs `max_threads` as well"), "{}", err);
+    let err = compile_err("launch_both", &LAUNCH.replace("BOUNDS", "max_threads(256), threads(128)"), &[]);
+    assert!(err.contains("Kernel `hot`: `max_threads` and `threads` cannot be given together"), "{}", err);
+    let err = compile_err("launch_unknown", &LAUNCH.replace("BOUNDS", "blocks(4)"), &[]);
+    assert!(err.contains("unknown launch bound `blocks`"), "{}", err);
+}
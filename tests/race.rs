@@ -0,0 +1,77 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn rebinding_a_let_with_itself_terminates() {
+    let compiled = compile_ok("race_let_rebinding", "import src/arith.ru
+
+parfun<i> bump(x: i64, a: mut [i32]) {
+    let i = x;
+    let i = i + 1;
+    @store(a, i, 1);
+    return;
+}
+", &[]);
+    assert!(compiled.stderr.contains("warning: possible data race on `a` in parfun `bump`, the index does not depend on thread index `i`"),
+            "{}", compiled.stderr);
+}
+
+#[test]
+fn lets_are_resolved_when_bound() {
+    let compiled = compile_ok("race_let_shadowing", "import src/arith.ru
+
+parfun<i> shift(a: mut [i32]) {
+    let j = i;
+    let i = 0;
+    @store(a, j + 1, 1);
+    return;
+}
+", &[]);
+    assert!(!compiled.stderr.contains("possible data race"), "{}", compiled.stderr);
+}
+
+#[test]
+fn stores_at_the_thread_index_are_private() {
+    let compiled = compile_ok("race_private", "import src/arith.ru
+
+parfun<y, x> scale(w: i64, a: mut [i32]) {
+    let k = y * w + x;
+    @store(a, k, @load(a, k) + @load(a, k));
+    return;
+}
+", &[]);
+    assert!(!compiled.stderr.contains("warning"), "{}", compiled.stderr);
+}
+
+#[test]
+fn neighbours_need_a_barrier() {
+    let source = |sync: &str| format!("import src/arith.ru
+
+parfun<i> smooth(a: mut [i32]) {{
+    let v = @load(a, i + 1);
+    {}
+    @store(a, i, v);
+    return;
+}}
+", sync);
+    let racy = compile_ok("race_neighbours", &source(""), &[]);
+    assert!(racy.stderr.contains("possible data race on `a` in parfun `smooth`, `a` is loaded at"), "{}", racy.stderr);
+    let synced = compile_ok("race_neighbours_synced", &source("@sync();"), &[]);
+    assert!(!synced.stderr.contains("possible data race"), "{}", synced.stderr);
+}
+
+#[test]
+fn barriers_under_thread_dependent_conditions_deadlock() {
+    let compiled = compile_ok("race_divergent_sync", "import src/arith.ru
+
+parfun<i> half(a: mut [i32]) {
+    if i < 16 {
+        @sync();
+    }
+    @store(a, i, 0);
+    return;
+}
+", &[]);
+    assert!(compiled.stderr.contains("warning: `@sync` in parfun `half` is under the thread-dependent condition `lt(i, 16)`"), "{}", compiled.stderr);
+}
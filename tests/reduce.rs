@@ -0,0 +1,54 @@
+mod common;
+
+use common::*;
+
+const SUM: &str = "import src/arith.ru
+
+parfun<x> LAUNCH sum(a: [f32; n], out: mut [f32; 1]) {
+    let s = reduce<i in n>(@load(a, i), add, out, 0);
+}
+";
+
+fn steps(body: &str) -> usize {
+    body.lines().filter(|l| l.starts_with("reducestep") && !l.starts_with("reducestepped")).count()
+}
+
+#[test]
+fn scratch_is_sized_after_the_launch_bounds() {
+    let bounded = compile_ok("reduce_bounded", &SUM.replace("LAUNCH", "@[max_threads(256)]"), &[]);
+    assert!(bounded.ir.contains("@sum_reduce4_13 = internal addrspace(3) global [256 x float] undef"), "{}", bounded.ir);
+    assert_eq!(steps(function(&bounded, "sum")), 8);
+    assert!(assembles(&bounded, "nvptx64", "sm_70"));
+
+    let exact = compile_ok("reduce_exact", &SUM.replace("LAUNCH", "@[threads(32, 3)]"), &[]);
+    assert!(exact.ir.contains("global [96 x float] undef"), "{}", exact.ir);
+    assert_eq!(steps(function(&exact, "sum")), 7);
+    assert!(assembles(&exact, "nvptx64", "sm_70"));
+}
+
+#[test]
+fn kernels_without_launch_bounds_take_the_largest_blocks() {
+    let compiled = compile_ok("reduce_unbounded", &SUM.replace("LAUNCH ", ""), &[]);
+    assert!(compiled.ir.contains("global [1024 x float] undef"), "{}", compiled.ir);
+    assert_eq!(steps(function(&compiled, "sum")), 10);
+}
+
+#[test]
+fn block_totals_are_combined_atomically() {
+    let compiled = compile_ok("reduce_combine", &SUM.replace("LAUNCH ", ""), &[]);
+    let sum = function(&compiled, "sum");
+    assert!(sum.contains("atomicrmw fadd float addrspace(1)* %out, float %totaltmp monotonic"), "{}", sum);
+    assert!(sum.contains("call void @llvm.nvvm.barrier0()"));
+}
+
+#[test]
+fn errors_tell_the_value_is_block_local() {
+    let err = compile_err("reduce_bad_op", "import src/arith.ru
+
+parfun<x> sum(a: [f32; n], out: mut [f32; 1]) {
+    let s = reduce<i in n>(@load(a, i), multiply, out, 0);
+}
+", &[]);
+    assert!(err.contains("`reduce` cannot combine with `multiply`, only with add, min, max"), "{}", err);
+    assert!(err.contains("it evaluates to the total of the block of the calling thread only"), "{}", err);
+}
@@ -0,0 +1,152 @@
+mod common;
+
+use common::*;
+
+const TILE: &str = "import src/arith.ru
+
+parfun<i> LAUNCH stage(a: [i32], out: mut [i32]) {
+    shared t: [i32; 256];
+    @store(t, @thread_idx(0), @load(a, i));
+    @sync();
+    @store(out, i, @load(t, 255 - @thread_idx(0)));
+    return;
+}
+";
+
+#[test]
+fn guarded_accesses_are_proven() {
+    compile_ok("refine_guarded", "import src/arith.ru
+
+fun get(a: [i64; 16], k: i64) -> i64 {
+    if k >= 0 {
+        if k < 16 {
+            return @load(a, k);
+        }
+    }
+    return 0;
+}
+
+fun last(a: [i64; 16], k: i64) -> i64 {
+    if k >= 16 {
+        return 0;
+    }
+    if k < 0 {
+        return 0;
+    }
+    return @load(a, k);
+}
+", &[]);
+}
+
+#[test]
+fn rebinding_a_let_with_itself_terminates() {
+    let err = compile_err("refine_let_rebinding", "import src/arith.ru
+
+fun bump(a: [i32; 16], x: i64) -> i32 {
+    let i = x;
+    let i = i + 1;
+    return @load(a, i);
+}
+", &[]);
+    assert!(err.contains("cannot prove that index `(x + 1)` of `a` is within `0..16`"), "{}", err);
+}
+
+#[test]
+fn rebinding_a_name_drops_its_guards() {
+    let err = compile_err("refine_let_guards", "import src/arith.ru
+
+fun get(a: [i32; 16], k: i64, m: i64) -> i32 {
+    if k >= 0 {
+        if k < 16 {
+            let k = m;
+            return @load(a, k);
+        }
+    }
+    return 0;
+}
+", &[]);
+    assert!(err.contains("cannot prove that index `m` of `a` is within `0..16`, guard the access with `if k >= 0` and `if k < 16`"), "{}", err);
+}
+
+#[test]
+fn suggested_guards_are_proven() {
+    let err = compile_err("refine_tile", &TILE.replace("LAUNCH ", ""), &[]);
+    assert!(err.contains("index `@thread_idx(0)` of `t` is within `0..256`, guard the access with `if @thread_idx(0) < 256`, \
+                          declare at most 256 threads in dimension 0 with the `max_threads` launch bound or compile with --bounds-check"), "{}", err);
+    assert!(err.contains("index `(255 - @thread_idx(0))` of `t` is within `0..256`, \
+                          bind the index with `let k = ..` and guard the access with `if k >= 0` and `if k < 256`"), "{}", err);
+    compile_ok("refine_tile_guarded", "import src/arith.ru
+
+parfun<i> stage(a: [i32], out: mut [i32]) {
+    shared t: [i32; 256];
+    if @thread_idx(0) < 256 {
+        @store(t, @thread_idx(0), @load(a, i));
+    }
+    @sync();
+    let k = 255 - @thread_idx(0);
+    if k >= 0 {
+        if k < 256 {
+            @store(out, i, @load(t, k));
+        }
+    }
+    return;
+}
+", &[]);
+}
+
+#[test]
+fn launch_bounds_limit_thread_indices() {
+    compile_ok("refine_tile_bounded", &TILE.replace("LAUNCH", "@[max_threads(256)]"), &[]);
+    compile_ok("refine_tile_exact", &TILE.replace("LAUNCH", "@[threads(128, 2)]"), &[]);
+    let err = compile_err("refine_tile_large", &TILE.replace("LAUNCH", "@[max_threads(512)]"), &[]);
+    assert!(err.contains("index `@thread_idx(0)` of `t` is within `0..256`"), "{}", err);
+}
+
+#[test]
+fn bounds_check_accepts_unproven_accesses() {
+    compile_ok("refine_tile_checked", &TILE.replace("LAUNCH ", ""), &["--bounds-check"]);
+}
+
+const ATOMIC_GUARD: &str = "import src/arith.ru
+
+fun get(a: [i64; 16], c: mut [i64]) -> i64 {
+    let k = @atomic_add(c, 0, 1);
+    if k >= 0 {
+        if k < 16 {
+            let j = INDEX;
+            return @load(a, j);
+        }
+    }
+    return 0;
+}
+";
+
+#[test]
+fn guards_on_opaque_calls_do_not_cover_fresh_calls() {
+    let err = compile_err("refine_atomic_fresh", &ATOMIC_GUARD.replace("INDEX", "@atomic_add(c, 0, 1)"), &[]);
+    assert!(err.contains("cannot prove that index `@atomic_add(..)#2` of `a` is within `0..16`, guard the access with `if j >= 0` and `if j < 16`"), "{}", err);
+    let err = compile_err("refine_load_fresh", "import src/arith.ru
+
+fun get(a: [i64; 16], c: [i64]) -> i64 {
+    if @load(c, 0) >= 0 {
+        if @load(c, 0) < 16 {
+            return @load(a, @load(c, 0));
+        }
+    }
+    return 0;
+}
+", &[]);
+    assert!(err.contains("bind the index with `let k = ..` and guard the access with `if k >= 0` and `if k < 16`"), "{}", err);
+
+    let checked = compile_ok("refine_atomic_fresh_checked", &ATOMIC_GUARD.replace("INDEX", "@atomic_add(c, 0, 1)"), &["--bounds-check"]);
+    let get = function(&checked, "get");
+    assert_eq!(get.matches("atomicrmw add").count(), 2, "{}", get);
+    assert!(get.contains("call void @llvm.trap()"), "{}", get);
+}
+
+#[test]
+fn guards_on_a_bound_opaque_call_are_proven() {
+    let checked = compile_ok("refine_atomic_bound", &ATOMIC_GUARD.replace("INDEX", "k"), &["--bounds-check"]);
+    assert!(!function(&checked, "get").contains("@llvm.trap()"));
+    compile_ok("refine_atomic_bound_unchecked", &ATOMIC_GUARD.replace("INDEX", "k"), &[]);
+}
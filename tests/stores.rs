@@ -0,0 +1,53 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn writes_into_constants_are_reported_before_codegen() {
+    let err = compile_err("stores_constant", "import src/arith.ru
+
+parfun<i> scale(v: f32, a: mut [f32]) {
+    @store(coef, 0, v);
+    let c = coef;
+    @atomic_add(c, 1, v);
+    @store(a, i, @load(coef, 2));
+    return;
+}
+
+const coef: [f32; 16];
+", &[]);
+    assert!(err.contains("stores_constant.ru:4:5: `@store` cannot write into `coef` in constant memory, it is only written by the host before launching"), "{}", err);
+    assert!(err.contains("stores_constant.ru:6:5: `@atomic_add` cannot write into `c` in constant memory"), "{}", err);
+    assert!(err.contains("Function bodies failed to check, see the errors above"), "{}", err);
+    assert!(!err.contains("llvm_gen.rs"), "{}", err);
+}
+
+#[test]
+fn constants_are_read_from_constant_memory() {
+    let compiled = compile_ok("stores_constant_load", "import src/arith.ru
+
+const coef: [f32; 16];
+
+parfun<i> scale(a: mut [f32]) {
+    @store(a, i, @load(a, i) * @load(coef, 2));
+    return;
+}
+", &[]);
+    assert!(compiled.ir.contains("addrspace(4)"), "{}", compiled.ir);
+    assert!(assembles(&compiled, "nvptx64", "sm_70"));
+}
+
+#[test]
+fn writes_through_immutable_arrays_are_reported() {
+    let err = compile_err("stores_immutable", "import src/arith.ru
+
+parfun<i> clear(a: [i32], b: mut [i32]) {
+    let c = a;
+    @store(c, i, 0);
+    @store(b, i, 0);
+    return;
+}
+", &[]);
+    assert!(err.contains("stores_immutable.ru:5:5: `@store` cannot write into immutable array `c` of type [i32], declare it as `mut`"), "{}", err);
+    assert!(!err.contains("array `b`"), "{}", err);
+}